@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use tokio::{fs, task, time};
+use tracing::{error, info, warn};
+
+/// Number of failures in a row before a source is locked out.
+const MAX_FAILURES: u32 = 5;
+
+/// How long a source is locked out, in seconds.
+const LOCKOUT_TIME: u64 = 300;
+
+/// How long a source is remembered after its last failure, in seconds.
+const FAILURE_WINDOW: u64 = 900;
+
+/// Delay of the first failure, doubled for each following one.
+const FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+/// Credentials loaded from a htpasswd-style file, one `user:hash` per line.
+///
+/// Only bcrypt (`$2a$`, `$2b$`, `$2y$`) and argon2 (`$argon2id$`, ...) hashes are accepted,
+/// the file is reloaded when its modification time changes. Removing the file revokes every
+/// user, while a file which can not be read or parsed keeps the previous users.
+pub struct CredentialFile {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+}
+
+impl CredentialFile {
+    pub fn new(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+
+        let modified = modified_time(&path)?;
+        let users = load_users(&path)?;
+
+        info!("Load {} users from {}", users.len(), path.display());
+
+        Ok(Self {
+            path,
+            state: Mutex::new(State {
+                modified: Some(modified),
+                users,
+            }),
+        })
+    }
+
+    pub async fn verify(&self, user: &str, pass: &str) -> bool {
+        self.reload().await;
+
+        // unknown users are checked against another hash, so that the timing does not tell them apart
+        let (hash, known) = {
+            let state = self.state.lock().unwrap();
+
+            match state.users.get(user) {
+                Some(hash) => (hash.clone(), true),
+                None => match state.users.values().next() {
+                    Some(hash) => (hash.clone(), false),
+                    None => return false,
+                },
+            }
+        };
+
+        let pass = pass.to_string();
+
+        // password hashing is slow by design, keep it off the async workers
+        let valid = task::spawn_blocking(move || verify_hash(&hash, &pass))
+            .await
+            .unwrap_or(false);
+
+        valid && known
+    }
+
+    /// The file is read without blocking the async workers, it may be on a slow filesystem.
+    async fn reload(&self) {
+        let modified = match fs::metadata(&self.path).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut state = self.state.lock().unwrap();
+
+                if state.modified.is_some() {
+                    warn!("Revoke all users, {} was removed", self.path.display());
+                    state.users.clear();
+                    state.modified = None;
+                }
+                return;
+            }
+            Err(e) => {
+                error!("Failed to stat {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+
+        if self.state.lock().unwrap().modified == Some(modified) {
+            return;
+        }
+
+        // keep the previous users if the new file is broken
+        let users = fs::read_to_string(&self.path)
+            .await
+            .and_then(|content| parse_users(&content));
+
+        match users {
+            Ok(users) => {
+                info!("Reload {} users from {}", users.len(), self.path.display());
+
+                let mut state = self.state.lock().unwrap();
+                state.users = users;
+                state.modified = Some(modified);
+            }
+            Err(e) => error!("Failed to reload {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Where authentication attempts come from.
+///
+/// Reverse mode can not see the client addresses, the attempts are counted per user name there.
+/// Anyone reaching the reverse server can then lock a known user out for `LOCKOUT_TIME` with a
+/// few bad passwords, the legitimate clients of that user included.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Ip(ip) => write!(f, "{}", ip),
+            Source::User(user) => write!(f, "user {}", user),
+        }
+    }
+}
+
+/// Failed authentication counters per source.
+///
+/// Every failure delays the reply exponentially, and a source is locked out for a while
+/// after too many failures in a row.
+#[derive(Clone, Default)]
+pub struct Lockout {
+    sources: Arc<Mutex<HashMap<Source, Failures>>>,
+}
+
+struct Failures {
+    count: u32,
+    /// Attempts which are being verified, counted as failures until they are settled.
+    pending: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Lockout {
+    /// Reserve an attempt before verifying the credentials, refused when the source is locked
+    /// out or when the attempts in flight could reach `MAX_FAILURES`, so that parallel clients
+    /// do not get more guesses. Each reserved attempt is settled by `failed` or `succeeded`.
+    pub fn attempt(&self, source: &Source) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        let now = Instant::now();
+
+        // forget the sources which stopped failing
+        sources.retain(|_, failures| {
+            failures.pending > 0
+                || failures.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(failures.last) < Duration::from_secs(FAILURE_WINDOW)
+        });
+
+        let failures = sources.entry(source.clone()).or_insert(Failures {
+            count: 0,
+            pending: 0,
+            last: now,
+            locked_until: None,
+        });
+
+        if failures.locked_until.is_some_and(|until| until > now)
+            || failures.count + failures.pending >= MAX_FAILURES
+        {
+            return false;
+        }
+
+        failures.pending += 1;
+        true
+    }
+
+    /// Record a failure, then wait before the failure is reported to the client.
+    pub async fn failed(&self, source: &Source) {
+        let count = {
+            let mut sources = self.sources.lock().unwrap();
+            let now = Instant::now();
+
+            let failures = sources.entry(source.clone()).or_insert(Failures {
+                count: 0,
+                pending: 0,
+                last: now,
+                locked_until: None,
+            });
+
+            failures.pending = failures.pending.saturating_sub(1);
+            failures.count += 1;
+            failures.last = now;
+
+            if failures.count >= MAX_FAILURES {
+                warn!(
+                    "Lock out {} for {} seconds after {} authentication failures",
+                    source, LOCKOUT_TIME, failures.count
+                );
+                failures.count = 0;
+                failures.locked_until = Some(now + Duration::from_secs(LOCKOUT_TIME));
+            }
+
+            failures.count
+        };
+
+        let delay = FAILURE_DELAY * 2u32.pow(count.saturating_sub(1).min(MAX_FAILURES));
+        time::sleep(delay).await;
+    }
+
+    pub fn succeeded(&self, source: &Source) {
+        let mut sources = self.sources.lock().unwrap();
+
+        // keep the attempts still in flight reserved
+        if let Some(failures) = sources.get_mut(source) {
+            failures.pending = failures.pending.saturating_sub(1);
+
+            if failures.pending == 0 {
+                sources.remove(source);
+            } else {
+                failures.count = 0;
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
+}
+
+fn load_users(path: &Path) -> Result<HashMap<String, String>> {
+    parse_users(&std::fs::read_to_string(path)?)
+}
+
+fn parse_users(content: &str) -> Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (user, hash) = line.split_once(':').ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid credential at line {}", i + 1),
+            )
+        })?;
+
+        if !is_supported_hash(hash) {
+            warn!(
+                "Skip user {} at line {}, only bcrypt and argon2 hashes are supported",
+                user,
+                i + 1
+            );
+            continue;
+        }
+
+        users.insert(user.to_string(), hash.to_string());
+    }
+
+    Ok(users)
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$", "$argon2"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify_hash(hash: &str, pass: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .and_then(|hash| Argon2::default().verify_password(pass.as_bytes(), &hash))
+            .is_ok()
+    } else {
+        bcrypt::verify(pass, hash).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    fn source() -> Source {
+        Source::Ip("10.0.0.1".parse().unwrap())
+    }
+
+    /// Record a failure, returns how long the reply was delayed.
+    async fn fail(lockout: &Lockout, source: &Source) -> Duration {
+        assert!(lockout.attempt(source));
+
+        let start = time::Instant::now();
+        lockout.failed(source).await;
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn removed_file_revokes_users() {
+        let path = std::env::temp_dir().join(format!("pivot-users-{}.txt", std::process::id()));
+        let hash = bcrypt::hash("pass", 4).unwrap();
+        std::fs::write(&path, format!("alice:{}\n", hash)).unwrap();
+
+        let file = CredentialFile::new(path.to_str().unwrap()).unwrap();
+        assert!(file.verify("alice", "pass").await);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!file.verify("alice", "pass").await);
+
+        // the users are back once the file is restored
+        std::fs::write(&path, format!("alice:{}\n", hash)).unwrap();
+        assert!(file.verify("alice", "pass").await);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockout_threshold_and_delay() {
+        let lockout = Lockout::default();
+        let source = source();
+
+        // the delay doubles with each failure in a row
+        for i in 0..MAX_FAILURES - 1 {
+            assert_eq!(fail(&lockout, &source).await, FAILURE_DELAY * 2u32.pow(i));
+        }
+
+        fail(&lockout, &source).await;
+        assert!(!lockout.attempt(&source));
+
+        // the other sources are counted on their own
+        assert!(lockout.attempt(&Source::User("alice".to_string())));
+        assert!(lockout.attempt(&Source::Ip("10.0.0.2".parse().unwrap())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockout_expires() {
+        let lockout = Lockout::default();
+        let source = source();
+
+        for _ in 0..MAX_FAILURES {
+            fail(&lockout, &source).await;
+        }
+        assert!(!lockout.attempt(&source));
+
+        // the lockout is kept until LOCKOUT_TIME has passed
+        {
+            let mut sources = lockout.sources.lock().unwrap();
+            let until = sources[&source].locked_until.unwrap();
+            assert!(until > Instant::now() + Duration::from_secs(LOCKOUT_TIME - 10));
+            sources.get_mut(&source).unwrap().locked_until = Some(Instant::now());
+        }
+
+        // and the failures are counted from scratch again
+        assert_eq!(fail(&lockout, &source).await, FAILURE_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockout_reset_on_success() {
+        let lockout = Lockout::default();
+        let source = source();
+
+        for _ in 0..MAX_FAILURES - 1 {
+            fail(&lockout, &source).await;
+        }
+
+        assert!(lockout.attempt(&source));
+        lockout.succeeded(&source);
+
+        assert_eq!(fail(&lockout, &source).await, FAILURE_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockout_caps_parallel_attempts() {
+        let lockout = Lockout::default();
+        let source = source();
+        let verified = Arc::new(AtomicU32::new(0));
+
+        let mut attempts = JoinSet::new();
+
+        for _ in 0..MAX_FAILURES + 3 {
+            let (lockout, source, verified) = (lockout.clone(), source.clone(), verified.clone());
+
+            attempts.spawn(async move {
+                if !lockout.attempt(&source) {
+                    return;
+                }
+
+                // a slow verifier, every attempt is reserved before the first one fails
+                verified.fetch_add(1, Ordering::SeqCst);
+                time::sleep(Duration::from_millis(100)).await;
+                lockout.failed(&source).await;
+            });
+        }
+
+        attempts.join_all().await;
+
+        assert_eq!(verified.load(Ordering::SeqCst), MAX_FAILURES);
+        assert!(!lockout.attempt(&source));
+    }
+}
@@ -0,0 +1,565 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Error, ErrorKind, Result, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{error, info, warn};
+
+/// Raw IPv4 or IPv6 packets, without a link layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Address recorded when the real one is unknown, e.g. clients behind a reverse tunnel.
+pub const UNKNOWN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Largest payload put into a single synthesized TCP segment.
+const MAX_SEGMENT_SIZE: usize = 16384;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Number of packets waiting for the writer thread, packets are dropped beyond it.
+const QUEUE_SIZE: usize = 4096;
+
+/// Capture of the forwarded cleartext into pcapng files.
+///
+/// The payload is recorded after TLS termination, with synthesized IP and TCP/UDP headers
+/// carrying the client and target addresses of the pipe. When `max_size` is set, a new file
+/// `NAME-1.EXT`, `NAME-2.EXT`... is started once the current one would grow beyond it.
+///
+/// The files are written by a dedicated thread, so that the pipes never wait for the disk.
+#[derive(Clone)]
+pub struct Capture {
+    queue: Arc<Queue>,
+    listeners: Arc<Vec<String>>,
+}
+
+/// Packets on their way to the writer thread, which is joined once the last capture is dropped.
+struct Queue {
+    tx: Option<SyncSender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+    full: AtomicBool,
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    index: u32,
+    size: u64,
+    file: BufWriter<File>,
+}
+
+/// Direction of the data in a pipe, `Up` goes from the client to the target.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Synthesized TCP connection of a pipe, which keeps the sequence numbers of both directions.
+pub struct TcpFlow {
+    capture: Capture,
+    client: SocketAddr,
+    target: SocketAddr,
+    /// Next sequence number of the client and of the target.
+    seq: Mutex<[u32; 2]>,
+}
+
+impl Capture {
+    /// Create the capture file, `listeners` limits the capture to the pipes of the listeners
+    /// bound to these addresses (format: [IP:]PORT), or every pipe when empty.
+    pub fn new(path: &str, max_size: u64, listeners: Vec<String>) -> Result<Self> {
+        for listener in &listeners {
+            if listener.parse::<SocketAddr>().is_err() && listener.parse::<u16>().is_err() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid capture listener: {}", listener),
+                ));
+            }
+        }
+
+        let (file, size) = open(Path::new(path))?;
+
+        let writer = Writer {
+            path: PathBuf::from(path),
+            max_size,
+            index: 0,
+            size,
+            file,
+        };
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let thread = thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || writer.run(rx))?;
+
+        info!("Capture pipes to {}", path);
+
+        Ok(Self {
+            queue: Arc::new(Queue {
+                tx: Some(tx),
+                thread: Some(thread),
+                full: AtomicBool::new(false),
+            }),
+            listeners: Arc::new(listeners),
+        })
+    }
+
+    /// Whether the pipes of a listener bound to any of `addrs` are captured.
+    pub fn captures(&self, addrs: &[SocketAddr]) -> bool {
+        if self.listeners.is_empty() {
+            return true;
+        }
+
+        self.listeners.iter().any(|listener| {
+            addrs
+                .iter()
+                .any(|addr| match listener.parse::<SocketAddr>() {
+                    Ok(listener) => {
+                        listener.port() == addr.port()
+                            && listener.ip().to_canonical() == addr.ip().to_canonical()
+                    }
+                    Err(_) => listener.parse() == Ok(addr.port()),
+                })
+        })
+    }
+
+    /// Start a TCP connection, its handshake is recorded right away.
+    pub fn tcp(&self, client: SocketAddr, target: SocketAddr) -> TcpFlow {
+        let flow = TcpFlow {
+            capture: self.clone(),
+            client,
+            target,
+            seq: Mutex::new([1, 1]),
+        };
+
+        flow.segment(Direction::Up, 0, 0, TCP_SYN, &[]);
+        flow.segment(Direction::Down, 0, 1, TCP_SYN | TCP_ACK, &[]);
+        flow.segment(Direction::Up, 1, 1, TCP_ACK, &[]);
+
+        flow
+    }
+
+    /// Record a UDP datagram.
+    pub fn udp(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let mut datagram = Vec::with_capacity(8 + data.len());
+        datagram.extend_from_slice(&src.port().to_be_bytes());
+        datagram.extend_from_slice(&dst.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+
+        self.write(src, dst, 17, datagram, 6);
+    }
+
+    /// Record a TCP segment or UDP datagram, `checksum` is the offset of its checksum field.
+    fn write(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        protocol: u8,
+        payload: Vec<u8>,
+        checksum: usize,
+    ) {
+        if let Some(packet) = ip_packet(src, dst, protocol, payload, checksum) {
+            self.queue.send(packet_block(&packet));
+        }
+    }
+}
+
+impl Queue {
+    /// Hand a block to the writer thread, without waiting when the queue is full.
+    fn send(&self, block: Vec<u8>) {
+        let tx = self.tx.as_ref().unwrap();
+
+        match tx.try_send(block) {
+            Ok(()) => self.full.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.full.swap(true, Ordering::Relaxed) {
+                    warn!("Capture queue is full, drop packets");
+                }
+            }
+            // the writer stopped after a failed write
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Drop for Queue {
+    /// Let the writer thread write the queued packets and flush the file before exiting.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl TcpFlow {
+    /// Record data sent in `direction`.
+    pub fn data(&self, direction: Direction, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT_SIZE) {
+            let (seq, ack) = self.advance(direction, chunk.len() as u32);
+            self.segment(direction, seq, ack, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    /// Record the EOF of `direction`.
+    pub fn fin(&self, direction: Direction) {
+        let (seq, ack) = self.advance(direction, 1);
+        self.segment(direction, seq, ack, TCP_FIN | TCP_ACK, &[]);
+    }
+
+    /// Record an abnormal close of the pipe.
+    pub fn reset(&self) {
+        let (seq, ack) = self.advance(Direction::Up, 0);
+        self.segment(Direction::Up, seq, ack, TCP_RST | TCP_ACK, &[]);
+    }
+
+    /// Take `len` sequence numbers of `direction`, returns the sequence and acknowledgment numbers.
+    fn advance(&self, direction: Direction, len: u32) -> (u32, u32) {
+        let mut seq = self.seq.lock().unwrap();
+
+        let (own, peer) = match direction {
+            Direction::Up => (0, 1),
+            Direction::Down => (1, 0),
+        };
+
+        let current = seq[own];
+        seq[own] = current.wrapping_add(len);
+
+        (current, seq[peer])
+    }
+
+    fn segment(&self, direction: Direction, seq: u32, ack: u32, flags: u8, data: &[u8]) {
+        let (src, dst) = match direction {
+            Direction::Up => (self.client, self.target),
+            Direction::Down => (self.target, self.client),
+        };
+
+        let mut segment = Vec::with_capacity(20 + data.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.extend_from_slice(data);
+
+        self.capture.write(src, dst, 6, segment, 16);
+    }
+}
+
+impl Writer {
+    /// Write the queued blocks until every capture is dropped, the buffer is flushed whenever
+    /// the queue is empty. The capture stops after a failed write.
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        while let Ok(block) = rx.recv() {
+            let mut result = self.write_block(&block);
+
+            while result.is_ok() {
+                match rx.try_recv() {
+                    Ok(block) => result = self.write_block(&block),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = result.and_then(|_| self.file.flush()) {
+                error!("Failed to write capture, stop capturing: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        if self.max_size > 0 && self.size + block.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(block)?;
+        self.size += block.len() as u64;
+
+        Ok(())
+    }
+
+    /// Finish the current file and start the next one.
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+
+        self.index += 1;
+        let path = rotated_path(&self.path, self.index);
+        (self.file, self.size) = open(&path)?;
+
+        info!("Rotate capture to {}", path.display());
+
+        Ok(())
+    }
+}
+
+/// Create a capture file with the section header and interface description blocks, returns
+/// the file and the size written.
+fn open(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let mut header = Vec::new();
+
+    // section header block: byte order magic, version 1.0, unknown section length
+    block(&mut header, 0x0a0d0d0a, |body| {
+        body.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+    });
+
+    // interface description block: microsecond timestamps by default, no snapshot length
+    block(&mut header, 0x00000001, |body| {
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+    });
+
+    file.write_all(&header)?;
+
+    Ok((file, header.len() as u64))
+}
+
+/// Wrap a TCP segment or UDP datagram into an IP packet, `checksum` is the offset of its checksum field.
+fn ip_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    protocol: u8,
+    mut payload: Vec<u8>,
+    checksum: usize,
+) -> Option<Vec<u8>> {
+    let (src, dst) = match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => (IpAddr::V4(src), IpAddr::V4(dst)),
+        // mixed families are recorded as IPv4-mapped IPv6 addresses
+        (src, dst) => (IpAddr::V6(to_ipv6(src)), IpAddr::V6(to_ipv6(dst))),
+    };
+
+    // the length fields of the headers are 16 bits
+    let len = match u16::try_from(payload.len() + 40) {
+        Ok(_) => payload.len(),
+        Err(_) => return None,
+    };
+
+    let mut pseudo = Vec::with_capacity(40);
+    let mut packet = Vec::with_capacity(40 + len);
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, protocol]);
+            pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            let sum = checksum16(&[&packet]);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (src, dst) => {
+            let (src, dst) = (to_ipv6(src).octets(), to_ipv6(dst).octets());
+
+            pseudo.extend_from_slice(&src);
+            pseudo.extend_from_slice(&dst);
+            pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, protocol]);
+
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(len as u16).to_be_bytes());
+            packet.extend_from_slice(&[protocol, 64]);
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+        }
+    }
+
+    let sum = match checksum16(&[&pseudo, &payload]) {
+        // a zero UDP checksum means no checksum
+        0 if protocol == 17 => 0xffff,
+        sum => sum,
+    };
+    payload[checksum..checksum + 2].copy_from_slice(&sum.to_be_bytes());
+
+    packet.extend_from_slice(&payload);
+
+    Some(packet)
+}
+
+/// Enhanced packet block of a packet captured now.
+fn packet_block(packet: &[u8]) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut data = Vec::with_capacity(32 + packet.len() + 3);
+
+    block(&mut data, 0x00000006, |body| {
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+    });
+
+    data
+}
+
+/// Append a pcapng block, the body is padded to 32 bits.
+fn block(buf: &mut Vec<u8>, block_type: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&[0; 4]);
+
+    body(buf);
+
+    buf.resize(buf.len().next_multiple_of(4), 0);
+
+    let len = (buf.len() - start + 4) as u32;
+    buf[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+/// `capture.pcapng` is rotated to `capture-1.pcapng`, `capture-2.pcapng`...
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+
+    path.with_file_name(name)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum (RFC 1071) over the concatenated parts.
+fn checksum16(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+
+    for byte in parts.iter().flat_map(|part| part.iter()).enumerate() {
+        sum += match byte {
+            (i, &b) if i % 2 == 0 => (b as u32) << 8,
+            (_, &b) => b as u32,
+        };
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_datagram(data: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0x30, 0x39, 0x00, 0x35];
+        datagram.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    #[test]
+    fn checksum_rfc1071() {
+        // example of RFC 1071 section 3, the sum is 0xddf2
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum16(&[&data]), !0xddf2);
+
+        // the parts are summed as one buffer, even when split at odd offsets
+        assert_eq!(checksum16(&[&data[..3], &data[3..]]), !0xddf2);
+
+        // an odd length is padded with a zero byte
+        assert_eq!(checksum16(&[&[0x12]]), !0x1200);
+    }
+
+    #[test]
+    fn ipv4_checksums() {
+        let src = "10.0.0.1:12345".parse().unwrap();
+        let dst = "10.0.0.2:53".parse().unwrap();
+
+        let packet = ip_packet(src, dst, 17, udp_datagram(b"hello"), 6).unwrap();
+        assert_eq!(packet.len(), 20 + 8 + 5);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 33);
+
+        // a valid checksum sums to zero with the data it covers
+        assert_eq!(checksum16(&[&packet[..20]]), 0);
+
+        let mut pseudo = packet[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 17, 0, 13]);
+        assert_eq!(checksum16(&[&pseudo, &packet[20..]]), 0);
+    }
+
+    #[test]
+    fn ipv6_checksums() {
+        let src = "[2001:db8::1]:12345".parse().unwrap();
+        // mixed families are recorded as IPv6
+        let dst = "10.0.0.2:53".parse().unwrap();
+
+        let packet = ip_packet(src, dst, 17, udp_datagram(b"hello"), 6).unwrap();
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(packet.len(), 40 + 8 + 5);
+        assert_eq!(&packet[24..40], &to_ipv6(dst.ip()).octets());
+
+        let mut pseudo = packet[8..40].to_vec();
+        pseudo.extend_from_slice(&[0, 0, 0, 13, 0, 0, 0, 17]);
+        assert_eq!(checksum16(&[&pseudo, &packet[40..]]), 0);
+    }
+
+    #[test]
+    fn oversized_payload() {
+        let src = "10.0.0.1:1".parse().unwrap();
+        let dst = "10.0.0.2:2".parse().unwrap();
+
+        assert!(ip_packet(src, dst, 6, vec![0; 65535], 16).is_none());
+    }
+
+    #[test]
+    fn block_padding() {
+        let mut buf = Vec::new();
+        block(&mut buf, 0x00000006, |body| {
+            body.extend_from_slice(&[1, 2, 3])
+        });
+
+        // type, length, body padded to 32 bits, length again
+        assert_eq!(buf.len(), 16);
+        assert_eq!(&buf[..4], &6u32.to_le_bytes());
+        assert_eq!(&buf[4..8], &16u32.to_le_bytes());
+        assert_eq!(&buf[8..12], &[1, 2, 3, 0]);
+        assert_eq!(&buf[12..], &16u32.to_le_bytes());
+    }
+
+    #[test]
+    fn rotation_names() {
+        let path = Path::new("/tmp/capture.pcapng");
+        assert_eq!(rotated_path(path, 2), Path::new("/tmp/capture-2.pcapng"));
+        assert_eq!(rotated_path(Path::new("dump"), 1), Path::new("dump-1"));
+    }
+}
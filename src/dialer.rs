@@ -0,0 +1,295 @@
+use std::{
+    future::Future,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, select, task::JoinSet, time};
+
+use crate::{
+    dns::Resolver,
+    http,
+    socks::{self, TargetAddr},
+};
+
+/// Delay before starting the next connection attempt, see RFC 8305 section 5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Outbound TCP dialer, connects directly or through an upstream proxy.
+///
+/// The upstream proxy is given as a URL: `socks5://[user:pass@]host:port` or
+/// `http://[user:pass@]host:port`. Domain targets are passed to the upstream proxy
+/// unresolved, so that names are resolved on the far side of the chain.
+///
+/// Direct connections race every resolved address with Happy Eyeballs (RFC 8305).
+#[derive(Clone, Default)]
+pub struct Dialer {
+    upstream: Option<Upstream>,
+    resolver: Resolver,
+    timeout: Option<Duration>,
+}
+
+#[derive(Clone)]
+struct Upstream {
+    scheme: Scheme,
+    addr: TargetAddr,
+    auth: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    Socks5,
+    Http,
+}
+
+impl Dialer {
+    /// A `timeout` of `None` waits for the OS to give up.
+    pub fn new(
+        upstream: Option<&str>,
+        resolver: Resolver,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let upstream = upstream.map(parse_upstream).transpose()?;
+
+        Ok(Self {
+            upstream,
+            resolver,
+            timeout,
+        })
+    }
+
+    /// Same dialer with another upstream proxy, or none.
+    pub fn with_upstream(&self, upstream: Option<&str>) -> Result<Self> {
+        Self::new(upstream, self.resolver.clone(), self.timeout)
+    }
+
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Whether domain targets are resolved by an upstream proxy instead of the local resolver.
+    pub fn has_upstream(&self) -> bool {
+        self.upstream.is_some()
+    }
+
+    pub async fn connect(&self, target: &TargetAddr) -> Result<TcpStream> {
+        self.with_timeout(target, self.connect_target(target)).await
+    }
+
+    /// Connect to one of the addresses a target has already been resolved to.
+    pub async fn connect_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+    ) -> Result<TcpStream> {
+        self.with_timeout(target, async {
+            if self.upstream.is_none() {
+                return happy_eyeballs(addrs).await;
+            }
+
+            let mut last_err = None;
+
+            for addr in addrs {
+                match self.connect_target(&TargetAddr::Ip(*addr)).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+
+            Err(last_err.unwrap_or_else(no_address))
+        })
+        .await
+    }
+
+    async fn with_timeout(
+        &self,
+        target: &TargetAddr,
+        connect: impl Future<Output = Result<TcpStream>>,
+    ) -> Result<TcpStream> {
+        match self.timeout {
+            Some(timeout) => time::timeout(timeout, connect).await.map_err(|_| {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Connect to {} timed out", target),
+                )
+            })?,
+            None => connect.await,
+        }
+    }
+
+    async fn connect_target(&self, target: &TargetAddr) -> Result<TcpStream> {
+        let upstream = match &self.upstream {
+            Some(upstream) => upstream,
+            None => return self.connect_direct(target).await,
+        };
+
+        let mut stream = self.connect_direct(&upstream.addr).await.map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to connect to upstream {}: {}", upstream.addr, e),
+            )
+        })?;
+
+        let auth = upstream
+            .auth
+            .as_ref()
+            .map(|(user, pass)| (user.as_str(), pass.as_str()));
+
+        match upstream.scheme {
+            Scheme::Socks5 => socks::connect_through(&mut stream, target, auth).await?,
+            Scheme::Http => http::connect_through(&mut stream, target, auth).await?,
+        }
+
+        Ok(stream)
+    }
+
+    /// Connect without the upstream proxy.
+    async fn connect_direct(&self, target: &TargetAddr) -> Result<TcpStream> {
+        match target {
+            TargetAddr::Ip(addr) => TcpStream::connect(addr).await,
+            TargetAddr::Domain(domain, port) => {
+                happy_eyeballs(&self.resolver.lookup(domain, *port).await?).await
+            }
+        }
+    }
+}
+
+/// Race connection attempts over the addresses, starting a new attempt every
+/// `CONNECTION_ATTEMPT_DELAY` or as soon as the previous one fails, the first success wins.
+async fn happy_eyeballs(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+
+    if let Some(addr) = pending.next() {
+        attempts.spawn(TcpStream::connect(addr));
+    }
+
+    while !attempts.is_empty() {
+        select! {
+            Some(result) = attempts.join_next() => match result {
+                // the remaining attempts are aborted when the set is dropped
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    last_err = Some(e);
+
+                    if let Some(addr) = pending.next() {
+                        attempts.spawn(TcpStream::connect(addr));
+                    }
+                }
+                Err(e) => last_err = Some(e.into()),
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.as_slice().is_empty() => {
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_address))
+}
+
+/// Alternate the address families, starting with the family of the first address (RFC 8305 section 4).
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v4 = addrs.first().is_some_and(|addr| addr.is_ipv4());
+
+    let (primary, secondary): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv4() == first_v4);
+
+    let mut primary = primary.into_iter();
+    let mut secondary = secondary.into_iter();
+    let mut result = Vec::with_capacity(addrs.len());
+
+    loop {
+        match (primary.next(), secondary.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    result
+}
+
+fn no_address() -> Error {
+    Error::new(ErrorKind::NotFound, "No address to connect to")
+}
+
+/// Parse `scheme://[user:pass@]host:port`.
+fn parse_upstream(url: &str) -> Result<Upstream> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid upstream {}", url));
+
+    let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+
+    let scheme = match scheme.to_lowercase().as_str() {
+        "socks5" | "socks5h" => Scheme::Socks5,
+        "http" => Scheme::Http,
+        _ => return Err(invalid()),
+    };
+
+    let rest = rest.trim_end_matches('/');
+
+    let (auth, host) = match rest.rsplit_once('@') {
+        Some((userinfo, host)) => {
+            let (user, pass) = userinfo.split_once(':').ok_or_else(invalid)?;
+            (Some((user.to_string(), pass.to_string())), host)
+        }
+        None => (None, rest),
+    };
+
+    Ok(Upstream {
+        scheme,
+        addr: host.parse().map_err(|_| invalid())?,
+        auth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_families() {
+        let v6_first = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "1.0.0.1:1", "1.0.0.2:1"]);
+        assert_eq!(
+            interleave(&v6_first),
+            addrs(&["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "[::3]:1"])
+        );
+
+        let v4_first = addrs(&["1.0.0.1:1", "1.0.0.2:1", "[::1]:1"]);
+        assert_eq!(
+            interleave(&v4_first),
+            addrs(&["1.0.0.1:1", "[::1]:1", "1.0.0.2:1"])
+        );
+
+        assert!(interleave(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_falls_through() {
+        // a port which refuses connections
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = open.local_addr().unwrap();
+
+        let stream = happy_eyeballs(&[closed_addr, open_addr]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open_addr);
+
+        // the error of the last attempt is returned when every one fails
+        let e = happy_eyeballs(&[closed_addr]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+
+        let e = happy_eyeballs(&[]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+}
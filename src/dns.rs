@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use clap::ValueEnum;
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    system_conf, TokioAsyncResolver,
+};
+use tokio::net;
+use tracing::{info, warn};
+
+use crate::util;
+
+/// Number of records kept by the DNS cache, entries expire with their TTL.
+const CACHE_SIZE: usize = 1024;
+
+/// Address family tried first when a domain has both A and AAAA records.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Prefer {
+    Ipv4,
+    Ipv6,
+}
+
+/// Domain resolver shared by every outbound dial.
+///
+/// Names are looked up in the static hosts table first, then sent to the custom DNS servers or
+/// to the servers of the system configuration, both with a TTL-respecting cache. The resolver
+/// of the OS is used when the system configuration can not be read.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    dns: Option<TokioAsyncResolver>,
+    prefer: Option<Prefer>,
+}
+
+impl Resolver {
+    /// `servers` have the format `[udp://|tcp://]IP[:PORT]`, `hosts` the format `NAME=IP`,
+    /// and `hosts_file` the format of `/etc/hosts`.
+    pub fn new(
+        servers: &[String],
+        hosts: &[String],
+        hosts_file: Option<&str>,
+        prefer: Option<Prefer>,
+    ) -> Result<Self> {
+        let mut table: HashMap<String, Vec<IpAddr>> = HashMap::new();
+
+        if let Some(file) = hosts_file {
+            for line in util::read_config_lines(file)? {
+                // strip trailing comments
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+
+                let ip = fields
+                    .next()
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or_else(|| invalid("hosts entry", line))?;
+
+                for name in fields {
+                    table.entry(name.to_lowercase()).or_default().push(ip);
+                }
+            }
+        }
+
+        for host in hosts {
+            let (name, ip) = host
+                .split_once('=')
+                .and_then(|(name, ip)| Some((name, ip.parse().ok()?)))
+                .ok_or_else(|| invalid("host", host))?;
+
+            table.entry(name.to_lowercase()).or_default().push(ip);
+        }
+
+        if !table.is_empty() {
+            info!("Load {} static hosts", table.len());
+        }
+
+        let dns = match servers.is_empty() {
+            true => match system_conf::read_system_conf() {
+                Ok((config, mut opts)) => {
+                    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+                    opts.cache_size = CACHE_SIZE;
+
+                    Some(TokioAsyncResolver::tokio(config, opts))
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to read system DNS config, resolve without cache: {}",
+                        e
+                    );
+                    None
+                }
+            },
+            false => {
+                let name_servers = servers
+                    .iter()
+                    .map(|server| parse_server(server))
+                    .collect::<Result<Vec<_>>>()?;
+
+                for server in &name_servers {
+                    info!(
+                        "Use DNS server {} over {}",
+                        server.socket_addr, server.protocol
+                    );
+                }
+
+                let config = ResolverConfig::from_parts(None, vec![], name_servers);
+
+                let mut opts = ResolverOpts::default();
+                opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+                opts.cache_size = CACHE_SIZE;
+                opts.use_hosts_file = false;
+
+                Some(TokioAsyncResolver::tokio(config, opts))
+            }
+        };
+
+        Ok(Self {
+            hosts: Arc::new(table),
+            dns,
+            prefer,
+        })
+    }
+
+    /// Resolve a domain, failures are reported as `NotFound`.
+    pub async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let mut addrs: Vec<SocketAddr> = match self.hosts.get(&domain.to_lowercase()) {
+            Some(ips) => ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect(),
+            None => match &self.dns {
+                Some(dns) => dns
+                    .lookup_ip(domain)
+                    .await
+                    .map(|lookup| lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+                    .map_err(|e| not_found(domain, e))?,
+                None => net::lookup_host((domain, port))
+                    .await
+                    .map(|addrs| addrs.collect())
+                    .map_err(|e| not_found(domain, e))?,
+            },
+        };
+
+        if addrs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Failed to resolve {}: no address", domain),
+            ));
+        }
+
+        // the sort is stable, so the resolver order is kept within each family
+        if let Some(prefer) = self.prefer {
+            addrs.sort_by_key(|addr| addr.is_ipv4() != (prefer == Prefer::Ipv4));
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Parse `[udp://|tcp://]IP[:PORT]`, the port defaults to 53.
+fn parse_server(server: &str) -> Result<NameServerConfig> {
+    let (protocol, addr) = match server.split_once("://") {
+        Some(("udp", addr)) => (Protocol::Udp, addr),
+        Some(("tcp", addr)) => (Protocol::Tcp, addr),
+        Some(_) => return Err(invalid("DNS server", server)),
+        None => (Protocol::Udp, server),
+    };
+
+    let addr = match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(addr.parse().map_err(|_| invalid("DNS server", server))?, 53),
+    };
+
+    Ok(NameServerConfig::new(addr, protocol))
+}
+
+fn not_found(domain: &str, e: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("Failed to resolve {}: {}", domain, e),
+    )
+}
+
+fn invalid(what: &str, s: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Invalid {}: {}", what, s))
+}
@@ -1,16 +1,19 @@
-use std::{io::Result, sync::Arc};
+use std::{io::Result, net::SocketAddr, sync::Arc};
 
-use tokio::{
-    join,
-    net::{TcpListener, TcpStream, UdpSocket},
-    sync,
-};
+use tokio::{join, net::UdpSocket, sync};
 use tracing::{error, info};
 
 #[cfg(target_family = "unix")]
-use tokio::net::UnixStream;
-
-use crate::{crypto, tcp, udp};
+use crate::transport::{Dialer as _, UnixDialer};
+
+use crate::{
+    dialer::Dialer,
+    socks::TargetAddr,
+    tcp::{self, PipeConfig},
+    transport, udp,
+    util::Backoff,
+    ws,
+};
 
 pub struct Forward {
     local_addrs: Vec<String>,
@@ -20,9 +23,13 @@ pub struct Forward {
     #[cfg(target_family = "unix")]
     socket: Option<String>,
     udp: bool,
+    dialer: Dialer,
+    pipe: PipeConfig,
+    ws: ws::Config,
 }
 
 impl Forward {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         local_addrs: Vec<String>,
         remote_addrs: Vec<String>,
@@ -30,6 +37,9 @@ impl Forward {
         remote_opts: Vec<bool>,
         #[cfg(target_family = "unix")] socket: Option<String>,
         udp: bool,
+        dialer: Dialer,
+        pipe: PipeConfig,
+        ws: ws::Config,
     ) -> Self {
         Self {
             local_addrs,
@@ -39,6 +49,9 @@ impl Forward {
             #[cfg(target_family = "unix")]
             socket,
             udp,
+            dialer,
+            pipe,
+            ws,
         }
     }
 
@@ -93,16 +106,15 @@ impl Forward {
     }
 
     async fn local_to_local_tcp(&self) -> Result<()> {
-        let listener1 = TcpListener::bind(&self.local_addrs[0]).await?;
-        let listener2 = TcpListener::bind(&self.local_addrs[1]).await?;
+        let listener1 = transport::listen(&self.local_addrs[0], self.local_opts[0]).await?;
+        let listener2 = transport::listen(&self.local_addrs[1], self.local_opts[1]).await?;
 
         info!("Bind to {} success", listener1.local_addr()?);
-        info!("Bind to {} success", listener1.local_addr()?);
+        info!("Bind to {} success", listener2.local_addr()?);
 
-        let acceptor1 =
-            Arc::new(self.local_opts[0].then(|| crypto::get_tls_acceptor(&self.local_addrs[0])));
-        let acceptor2 =
-            Arc::new(self.local_opts[1].then(|| crypto::get_tls_acceptor(&self.local_addrs[1])));
+        let pipe = self
+            .pipe
+            .listener(&[listener1.local_addr()?, listener2.local_addr()?]);
 
         loop {
             let (r1, r2) = join!(listener1.accept(), listener2.accept());
@@ -113,91 +125,130 @@ impl Forward {
             info!("Accept connection from {}", addr1);
             info!("Accept connection from {}", addr2);
 
-            let acceptor1 = acceptor1.clone();
-            let acceptor2 = acceptor2.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let stream1 = tcp::NetStream::from_acceptor(stream1, acceptor1).await;
-                let stream2 = tcp::NetStream::from_acceptor(stream2, acceptor2).await;
+                let Some((stream1, stream2)) =
+                    transport::establish_pair((stream1, &addr1), (stream2, &addr2)).await
+                else {
+                    return;
+                };
 
                 info!("Open pipe: {} <=> {}", addr1, addr2);
-                if let Err(e) = tcp::handle_forward(stream1, stream2).await {
-                    error!("Failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", addr1, addr2);
+                let stats =
+                    tcp::handle_forward(stream1, stream2, &pipe.with_peers(addr1, addr2)).await;
+                info!("Close pipe: {} <=> {} ({})", addr1, addr2, stats);
             });
         }
     }
 
     async fn local_to_remote_tcp(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.local_addrs[0]).await?;
+        let listener = transport::listen(&self.local_addrs[0], self.local_opts[0]).await?;
         info!("Bind to {} success", listener.local_addr()?);
 
-        let acceptor =
-            Arc::new(self.local_opts[0].then(|| crypto::get_tls_acceptor(&self.local_addrs[0])));
+        let remote = transport::dial(
+            &self.remote_addrs[0],
+            self.remote_opts[0],
+            &self.dialer,
+            &self.ws,
+        )?;
 
-        let connector = Arc::new(self.remote_opts[0].then(|| crypto::get_tls_connector()));
+        let pipe = self.pipe.listener(&[listener.local_addr()?]);
 
         loop {
             let (client_stream, client_addr) = listener.accept().await?;
-            let remote_stream = TcpStream::connect(&self.remote_addrs[0]).await?;
-
-            let remote_addr = remote_stream.peer_addr()?;
-
             info!("Accept connection from {}", client_addr);
+
+            // drop the client, the target may be reachable again for the next one
+            let (remote_stream, remote_addr) = match remote.connect().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to connect to {}: {}", self.remote_addrs[0], e);
+                    continue;
+                }
+            };
             info!("Connect to {} success", remote_addr);
 
-            let acceptor = acceptor.clone();
-            let connector = connector.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let client_stream = tcp::NetStream::from_acceptor(client_stream, acceptor).await;
-                let remote_stream = tcp::NetStream::from_connector(remote_stream, connector).await;
+                let Some((client_stream, remote_stream)) = transport::establish_pair(
+                    (client_stream, &client_addr),
+                    (remote_stream, &remote_addr),
+                )
+                .await
+                else {
+                    return;
+                };
 
                 info!("Open pipe: {} <=> {}", client_addr, remote_addr);
-                if let Err(e) = tcp::handle_forward(client_stream, remote_stream).await {
-                    error!("failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", client_addr, remote_addr);
+                let pipe = pipe.with_peers(client_addr, remote_addr);
+                let stats = tcp::handle_forward(client_stream, remote_stream, &pipe).await;
+                info!(
+                    "Close pipe: {} <=> {} ({})",
+                    client_addr, remote_addr, stats
+                );
             });
         }
     }
 
     async fn remote_to_remote_tcp(&self) -> Result<()> {
-        let connector1 = Arc::new(self.remote_opts[0].then(|| crypto::get_tls_connector()));
-        let connector2 = Arc::new(self.remote_opts[1].then(|| crypto::get_tls_connector()));
+        let remote1 = transport::dial(
+            &self.remote_addrs[0],
+            self.remote_opts[0],
+            &self.dialer,
+            &self.ws,
+        )?;
+        let remote2 = transport::dial(
+            &self.remote_addrs[1],
+            self.remote_opts[1],
+            &self.dialer,
+            &self.ws,
+        )?;
 
         // limit the number of concurrent connections
         let semaphore = Arc::new(sync::Semaphore::new(32));
 
+        let pipe = self.pipe.listener(&[]);
+        let mut backoff = Backoff::default();
+
         loop {
             let permit = semaphore.clone().acquire_owned().await.unwrap();
 
-            let (r1, r2) = join!(
-                TcpStream::connect(&self.remote_addrs[0]),
-                TcpStream::connect(&self.remote_addrs[1])
-            );
+            let (r1, r2) = join!(remote1.connect(), remote2.connect());
 
-            let (stream1, stream2) = (r1?, r2?);
+            let ((stream1, addr1), (stream2, addr2)) = match (r1, r2) {
+                (Ok(v1), Ok(v2)) => (v1, v2),
+                (r1, r2) => {
+                    if let Err(e) = r1 {
+                        error!("Failed to connect to {}: {}", self.remote_addrs[0], e);
+                    }
+                    if let Err(e) = r2 {
+                        error!("Failed to connect to {}: {}", self.remote_addrs[1], e);
+                    }
 
-            let addr1 = stream1.peer_addr()?;
-            let addr2 = stream2.peer_addr()?;
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            backoff.succeeded();
 
             info!("Connect to {} success", addr1);
             info!("Connect to {} success", addr2);
 
-            let connector1 = connector1.clone();
-            let connector2 = connector2.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let stream1 = tcp::NetStream::from_connector(stream1, connector1).await;
-                let stream2 = tcp::NetStream::from_connector(stream2, connector2).await;
+                let Some((stream1, stream2)) =
+                    transport::establish_pair((stream1, &addr1), (stream2, &addr2)).await
+                else {
+                    return;
+                };
 
                 info!("Open pipe: {} <=> {}", addr1, addr2);
-                if let Err(e) = tcp::handle_forward(stream1, stream2).await {
-                    error!("Failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", addr1, addr2);
+                let stats =
+                    tcp::handle_forward(stream1, stream2, &pipe.with_peers(addr1, addr2)).await;
+                info!("Close pipe: {} <=> {} ({})", addr1, addr2, stats);
 
                 // drop the permit to release the semaphore
                 drop(permit);
@@ -207,70 +258,105 @@ impl Forward {
 
     #[cfg(target_family = "unix")]
     async fn socket_to_local_tcp(&self) -> Result<()> {
-        let local_listener = TcpListener::bind(&self.local_addrs[0]).await?;
+        let local_listener = transport::listen(&self.local_addrs[0], self.local_opts[0]).await?;
         info!("Bind to {} success", local_listener.local_addr()?);
 
-        let acceptor =
-            Arc::new(self.local_opts[0].then(|| crypto::get_tls_acceptor(&self.local_addrs[0])));
+        let unix_addr = self.socket.clone().unwrap();
+        let unix = UnixDialer::new(&unix_addr);
 
-        loop {
-            let unix_addr = self.socket.clone().unwrap();
+        let pipe = self.pipe.listener(&[local_listener.local_addr()?]);
 
+        loop {
             let (client_stream, client_addr) = local_listener.accept().await?;
-            let unix_stream = UnixStream::connect(&unix_addr).await?;
-
             info!("Accept connection from {}", client_addr);
+
+            // drop the client, the socket may be reachable again for the next one
+            let (unix_stream, unix_peer) = match unix.connect().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to connect to {}: {}", unix_addr, e);
+                    continue;
+                }
+            };
             info!("Connect to {} success", unix_addr);
 
-            let acceptor = acceptor.clone();
+            let unix_addr = unix_addr.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let client_stream = tcp::NetStream::from_acceptor(client_stream, acceptor).await;
-                let unix_stream = tcp::NetStream::Unix(unix_stream);
-
-                info!("Open pipe: {} <=> {}", unix_addr, client_addr);
-                if let Err(e) = tcp::handle_forward(client_stream, unix_stream).await {
-                    error!("Failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", unix_addr, client_addr);
+                let Some((client_stream, unix_stream)) = transport::establish_pair(
+                    (client_stream, &client_addr),
+                    (unix_stream, &unix_addr),
+                )
+                .await
+                else {
+                    return;
+                };
+
+                info!("Open pipe: {} <=> {}", client_addr, unix_addr);
+                let pipe = pipe.with_peers(client_addr, unix_peer);
+                let stats = tcp::handle_forward(client_stream, unix_stream, &pipe).await;
+                info!("Close pipe: {} <=> {} ({})", client_addr, unix_addr, stats);
             });
         }
     }
 
     #[cfg(target_family = "unix")]
     async fn socket_to_remote_tcp(&self) -> Result<()> {
-        let connector = Arc::new(self.remote_opts[0].then(|| crypto::get_tls_connector()));
+        let unix_addr = self.socket.clone().unwrap();
+        let remote_addr = self.remote_addrs[0].clone();
+
+        let unix = UnixDialer::new(&unix_addr);
+        let remote = transport::dial(&remote_addr, self.remote_opts[0], &self.dialer, &self.ws)?;
 
         // limit the number of concurrent connections
         let semaphore = Arc::new(sync::Semaphore::new(32));
 
+        let pipe = self.pipe.listener(&[]);
+        let mut backoff = Backoff::default();
+
         loop {
             let permit = semaphore.clone().acquire_owned().await.unwrap();
 
-            let unix_addr = self.socket.clone().unwrap();
-            let remote_addr = self.remote_addrs[0].clone();
+            let (r1, r2) = join!(unix.connect(), remote.connect());
 
-            let (r1, r2) = join!(
-                UnixStream::connect(&unix_addr),
-                TcpStream::connect(&remote_addr)
-            );
+            let ((unix_stream, unix_peer), (remote_stream, peer_addr)) = match (r1, r2) {
+                (Ok(v1), Ok(v2)) => (v1, v2),
+                (r1, r2) => {
+                    if let Err(e) = r1 {
+                        error!("Failed to connect to {}: {}", unix_addr, e);
+                    }
+                    if let Err(e) = r2 {
+                        error!("Failed to connect to {}: {}", remote_addr, e);
+                    }
 
-            let (unix_stream, remote_stream) = (r1?, r2?);
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            backoff.succeeded();
 
             info!("Connect to {} success", unix_addr);
             info!("Connect to {} success", remote_addr);
 
-            let connector = connector.clone();
+            let unix_addr = unix_addr.clone();
+            let remote_addr = remote_addr.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let unix_stream = tcp::NetStream::Unix(unix_stream);
-                let remote_stream = tcp::NetStream::from_connector(remote_stream, connector).await;
+                let Some((unix_stream, remote_stream)) = transport::establish_pair(
+                    (unix_stream, &unix_addr),
+                    (remote_stream, &remote_addr),
+                )
+                .await
+                else {
+                    return;
+                };
 
                 info!("Open pipe: {} <=> {}", unix_addr, remote_addr);
-                if let Err(e) = tcp::handle_forward(unix_stream, remote_stream).await {
-                    error!("Failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", unix_addr, remote_addr);
+                let pipe = pipe.with_peers(unix_peer, peer_addr);
+                let stats = tcp::handle_forward(unix_stream, remote_stream, &pipe).await;
+                info!("Close pipe: {} <=> {} ({})", unix_addr, remote_addr, stats);
 
                 // drop the permit to release the semaphore
                 drop(permit);
@@ -286,30 +372,52 @@ impl Forward {
         info!("Bind to {} success", self.local_addrs[1]);
 
         // socket1 will receive the handshake packet to keep client address
-        udp::handle_local_forward(socket1, socket2).await
+        let pipe = self
+            .pipe
+            .listener(&[socket1.local_addr()?, socket2.local_addr()?]);
+
+        udp::handle_local_forward(socket1, socket2, &pipe).await
     }
 
     async fn local_to_remote_udp(&self) -> Result<()> {
         let local_socket = UdpSocket::bind(&self.local_addrs[0]).await?;
         let remote_socket = UdpSocket::bind("0.0.0.0:0").await?;
 
-        remote_socket.connect(&self.remote_addrs[0]).await?;
+        remote_socket
+            .connect(
+                self.resolve_udp(&self.remote_addrs[0], &remote_socket)
+                    .await?,
+            )
+            .await?;
         info!("Connect to {} success", self.remote_addrs[0]);
 
-        udp::handle_local_to_remote_forward(local_socket, remote_socket).await
+        let pipe = self.pipe.listener(&[local_socket.local_addr()?]);
+
+        udp::handle_local_to_remote_forward(local_socket, remote_socket, &pipe).await
     }
 
     async fn remote_to_remote_udp(&self) -> Result<()> {
         let socket1 = UdpSocket::bind("0.0.0.0:0").await?;
         let socket2 = UdpSocket::bind("0.0.0.0:0").await?;
 
-        socket1.connect(&self.remote_addrs[0]).await?;
-        socket2.connect(&self.remote_addrs[1]).await?;
+        socket1
+            .connect(self.resolve_udp(&self.remote_addrs[0], &socket1).await?)
+            .await?;
+        socket2
+            .connect(self.resolve_udp(&self.remote_addrs[1], &socket2).await?)
+            .await?;
 
         info!("Connect to {} success", self.remote_addrs[0]);
         info!("Connect to {} success", self.remote_addrs[1]);
 
         // socket2 will send the handshake packet to keep client address
-        udp::handle_remote_forward(socket1, socket2).await
+        udp::handle_remote_forward(socket1, socket2, &self.pipe.listener(&[])).await
+    }
+
+    /// Resolve a remote address with the shared resolver, preferring the family of the socket.
+    async fn resolve_udp(&self, addr: &str, socket: &UdpSocket) -> Result<SocketAddr> {
+        addr.parse::<TargetAddr>()?
+            .resolve(self.dialer.resolver(), socket.local_addr()?)
+            .await
     }
 }
@@ -1,19 +1,50 @@
-use std::io::Result;
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    ops::RangeInclusive,
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
+use auth::Lockout;
+use capture::Capture;
+use clap::{Args, Parser, Subcommand};
+use dialer::Dialer;
+use dns::{Prefer, Resolver};
 use forward::Forward;
-use proxy::Proxy;
+use limit::RateLimits;
+use proxy::{Protocol, Proxy};
+use quota::Quotas;
+#[cfg(target_os = "linux")]
+use redir::Redir;
 use reuse::Reuse;
+use route::{AgentPool, Routes};
+use rules::Rules;
+use tcp::PipeConfig;
 use tracing::info;
 
+pub mod auth;
+pub mod capture;
 pub mod crypto;
+pub mod dialer;
+pub mod dns;
 pub mod forward;
+pub mod http;
+pub mod limit;
 pub mod proxy;
+pub mod quota;
+#[cfg(target_os = "linux")]
+pub mod redir;
 pub mod reuse;
+pub mod route;
+pub mod rules;
 pub mod socks;
+#[cfg(target_os = "linux")]
+pub mod splice;
 pub mod tcp;
+pub mod transport;
 pub mod udp;
 pub mod util;
+pub mod ws;
 
 #[derive(Parser)]
 #[command(author, version, about = "Pivot: Port-Forwarding and Proxy Tool")]
@@ -22,15 +53,17 @@ pub struct Cli {
     command: Commands,
 }
 
+// parsed once at startup, the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Port forwarding mode
     Fwd {
-        /// Local listen IP address, format: [+][IP:]PORT
+        /// Local listen IP address, format: [+][IP:]PORT or ws[s]://[IP:]PORT[/PATH]
         #[arg(short, long)]
         local: Vec<String>,
 
-        /// Remote connect IP address, format: [+]IP:PORT
+        /// Remote connect IP address, format: [+]IP:PORT or ws[s]://HOST[:PORT][/PATH]
         #[arg(short, long)]
         remote: Vec<String>,
 
@@ -42,21 +75,95 @@ pub enum Commands {
         /// Enable UDP forward mode
         #[arg(short, long)]
         udp: bool,
+
+        #[command(flatten)]
+        dial: DialOpts,
+
+        #[command(flatten)]
+        pipe: PipeOpts,
+
+        #[command(flatten)]
+        ws: WsOpts,
     },
 
-    /// Socks proxy mode
+    /// Socks and HTTP proxy mode
     Proxy {
-        /// Local listen IP address, format: [+][IP:]PORT
+        /// Local listen IP address, format: [+][IP:]PORT or ws[s]://[IP:]PORT[/PATH]
         #[arg(short, long)]
         local: Vec<String>,
 
-        /// Reverse server IP address, format: [+]IP:PORT
+        /// Reverse server IP address, format: [+]IP:PORT or ws[s]://HOST[:PORT][/PATH]
         #[arg(short, long)]
         remote: Option<String>,
 
         /// Authentication info, format: user:pass (other for random)
         #[arg(short, long)]
         auth: Option<String>,
+
+        /// Credentials file with bcrypt or argon2 hashes, format: user:hash per line
+        #[arg(long, conflicts_with = "auth")]
+        auth_file: Option<String>,
+
+        /// Proxy protocol
+        #[arg(short, long, value_enum, default_value_t = Protocol::Socks)]
+        protocol: Protocol,
+
+        /// Destination access rule, format: allow|deny DEST [PORTS] [USER]
+        #[arg(long)]
+        rule: Vec<String>,
+
+        /// Destination access rules file, one rule per line
+        #[arg(long)]
+        rules_file: Option<String>,
+
+        /// Listen IP address for BIND requests (default: the accepting interface)
+        #[arg(long)]
+        bind_ip: Option<IpAddr>,
+
+        /// Listen port range for BIND requests, format: PORT[-PORT] (default: random)
+        #[arg(long, value_parser = util::parse_port_range)]
+        bind_ports: Option<RangeInclusive<u16>>,
+
+        /// Per-user quota, format: USER [conns=N] [bytes=SIZE/PERIOD] [rate=SIZE]
+        #[arg(long)]
+        quota: Vec<String>,
+
+        /// Per-user quotas file, one quota per line
+        #[arg(long)]
+        quota_file: Option<String>,
+
+        /// File keeping the quota usage across restarts
+        #[arg(long)]
+        quota_state: Option<String>,
+
+        /// Destination route, format: direct|agent|block|UPSTREAM DEST [PORTS] [USER]
+        #[arg(long)]
+        route: Vec<String>,
+
+        /// Destination routes file, one route per line
+        #[arg(long)]
+        routes_file: Option<String>,
+
+        /// Resolve domain targets locally to match the IP and CIDR of agent and upstream routes
+        #[arg(long)]
+        route_resolve: bool,
+
+        /// Listen IP address of reverse agents for agent routes (pivot proxy -r), format: [+][IP:]PORT
+        #[arg(long)]
+        agent_listen: Option<String>,
+
+        /// Authentication info of reverse agents, format: user:pass
+        #[arg(long, requires = "agent_listen")]
+        agent_auth: Option<String>,
+
+        #[command(flatten)]
+        dial: DialOpts,
+
+        #[command(flatten)]
+        pipe: PipeOpts,
+
+        #[command(flatten)]
+        ws: WsOpts,
     },
 
     /// Port reuse mode
@@ -80,10 +187,168 @@ pub enum Commands {
         /// Timeout to stop port reuse
         #[arg(short, long)]
         timeout: Option<u64>,
+
+        #[command(flatten)]
+        dial: DialOpts,
+
+        #[command(flatten)]
+        pipe: PipeOpts,
     },
+
+    /// Transparent redirect mode (iptables/nftables REDIRECT)
+    #[cfg(target_os = "linux")]
+    Redir {
+        /// Local listen IP address of redirected connections, format: [IP:]PORT
+        #[arg(short, long)]
+        local: String,
+
+        /// Listen IP address of reverse agents (pivot proxy -r), format: [+][IP:]PORT
+        #[arg(short, long)]
+        control: Option<String>,
+
+        /// Authentication info of reverse agents, format: user:pass
+        #[arg(short, long, requires = "control")]
+        auth: Option<String>,
+
+        #[command(flatten)]
+        dial: DialOpts,
+
+        #[command(flatten)]
+        pipe: PipeOpts,
+    },
+}
+
+/// Options of outbound connections, shared by every mode.
+#[derive(Args)]
+pub struct DialOpts {
+    /// Upstream proxy for outbound connections, format: socks5|http://[user:pass@]HOST:PORT
+    #[arg(long)]
+    upstream: Option<String>,
+
+    /// DNS server for domain targets, format: [udp://|tcp://]IP[:PORT] (default: system resolver)
+    #[arg(long)]
+    dns: Vec<String>,
+
+    /// Static host entry overriding DNS, format: NAME=IP
+    #[arg(long)]
+    host: Vec<String>,
+
+    /// Static hosts file overriding DNS, in /etc/hosts format
+    #[arg(long)]
+    hosts_file: Option<String>,
+
+    /// Address family tried first when a domain has both A and AAAA records
+    #[arg(long, value_enum)]
+    prefer: Option<Prefer>,
+
+    /// Timeout in seconds of outbound connections, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    connect_timeout: u64,
+}
+
+impl DialOpts {
+    fn build(&self) -> Result<Dialer> {
+        let resolver = Resolver::new(
+            &self.dns,
+            &self.host,
+            self.hosts_file.as_deref(),
+            self.prefer,
+        )?;
+
+        let timeout = (self.connect_timeout > 0).then(|| Duration::from_secs(self.connect_timeout));
+
+        Dialer::new(self.upstream.as_deref(), resolver, timeout)
+    }
+}
+
+/// Timeouts and bandwidth limits of forwarded connections, shared by every mode.
+#[derive(Args)]
+pub struct PipeOpts {
+    /// Close TCP pipes idle in both directions for this many seconds, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// Close TCP pipes open for this many seconds, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    max_lifetime: u64,
+
+    /// Bandwidth limit in bytes per second, format: global|listener|conn [up=SIZE] [down=SIZE]
+    #[arg(long)]
+    rate_limit: Vec<String>,
+
+    /// Capture the cleartext of the pipes into a pcapng file
+    #[arg(long)]
+    capture: Option<String>,
+
+    /// Start a new capture file once the current one reaches this size, 0 to disable
+    #[arg(long, value_parser = util::parse_size, default_value = "0", requires = "capture")]
+    capture_size: u64,
+
+    /// Only capture the pipes of the listeners bound to this address, format: [IP:]PORT (default: every pipe)
+    #[arg(long, requires = "capture")]
+    capture_listen: Vec<String>,
 }
 
+impl PipeOpts {
+    fn build(&self) -> Result<PipeConfig> {
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+
+        Ok(PipeConfig {
+            idle_timeout: seconds(self.idle_timeout),
+            max_lifetime: seconds(self.max_lifetime),
+            limits: RateLimits::new(&self.rate_limit)?,
+            capture: match &self.capture {
+                Some(path) => Some(Capture::new(
+                    path,
+                    self.capture_size,
+                    self.capture_listen.clone(),
+                )?),
+                None => None,
+            },
+            peers: None,
+        })
+    }
+}
+
+/// Headers of the WebSocket upgrade requests sent to ws:// and wss:// remotes.
+#[derive(Args)]
+pub struct WsOpts {
+    /// Host header of WebSocket remotes, also sent as SNI for wss:// (default: the host of the address)
+    #[arg(long)]
+    ws_host: Option<String>,
+
+    /// Extra header of WebSocket remotes, format: NAME: VALUE
+    #[arg(long)]
+    ws_header: Vec<String>,
+}
+
+impl WsOpts {
+    fn build(&self) -> Result<ws::Config> {
+        ws::Config::new(self.ws_host.clone(), &self.ws_header)
+    }
+}
+
+/// Run the command until it fails or the process is asked to stop with SIGINT or SIGTERM.
+///
+/// The quota usage is saved on the way out, the tasks holding the other state (e.g. the capture)
+/// are dropped with the runtime afterwards.
 pub async fn run(cli: Cli) -> Result<()> {
+    let mut quotas = Quotas::default();
+
+    let result = tokio::select! {
+        result = run_command(cli, &mut quotas) => result,
+        _ = util::shutdown_signal() => {
+            info!("Shutting down");
+            Ok(())
+        }
+    };
+
+    quotas.save();
+    result
+}
+
+/// `quotas` is set to the quotas of the proxy mode, for `run` to save them.
+async fn run_command(cli: Cli, quotas: &mut Quotas) -> Result<()> {
     match cli.command {
         Commands::Fwd {
             local,
@@ -91,9 +356,24 @@ pub async fn run(cli: Cli) -> Result<()> {
             #[cfg(target_family = "unix")]
             socket,
             udp,
+            dial,
+            pipe,
+            ws,
         } => {
             info!("Starting forward mode");
 
+            if udp
+                && local
+                    .iter()
+                    .chain(&remote)
+                    .any(|addr| ws::Url::parse(addr).is_some())
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "WebSocket addresses require TCP forwarding",
+                ));
+            }
+
             if udp {
                 info!("Using UDP protocol");
             } else {
@@ -121,6 +401,9 @@ pub async fn run(cli: Cli) -> Result<()> {
                 #[cfg(target_family = "unix")]
                 socket,
                 udp,
+                dial.build()?,
+                pipe.build()?,
+                ws.build()?,
             );
 
             forward.start().await?;
@@ -129,6 +412,23 @@ pub async fn run(cli: Cli) -> Result<()> {
             local,
             remote,
             auth,
+            auth_file,
+            protocol,
+            rule,
+            rules_file,
+            bind_ip,
+            bind_ports,
+            quota,
+            quota_file,
+            quota_state,
+            route,
+            routes_file,
+            route_resolve,
+            agent_listen,
+            agent_auth,
+            dial,
+            pipe,
+            ws,
         } => {
             info!("Starting proxy mode");
 
@@ -145,9 +445,59 @@ pub async fn run(cli: Cli) -> Result<()> {
             let remote_addr = remote.as_ref().map(|addr| addr.replace("+", ""));
             let remote_opt = remote.is_some_and(|addr| addr.starts_with('+'));
 
-            let auth_info = auth.map(|v| socks::AuthInfo::new(v));
+            let auth_info = match auth_file {
+                Some(path) => Some(socks::AuthInfo::from_file(&path)?),
+                None => auth.map(socks::AuthInfo::new),
+            };
 
-            let proxy = Proxy::new(local_addrs, remote_addr, local_opts, remote_opt, auth_info);
+            let agents = match agent_listen {
+                Some(addr) => {
+                    let agent_auth = agent_auth
+                        .as_deref()
+                        .map(util::parse_credentials)
+                        .transpose()?;
+                    let agent_opt = addr.starts_with('+');
+                    let addr = addr.replace("+", "");
+                    let addr = match addr.contains(':') {
+                        true => addr,
+                        false => format!("0.0.0.0:{}", addr),
+                    };
+
+                    Some(AgentPool::listen(&addr, agent_opt, agent_auth).await?)
+                }
+                None => None,
+            };
+
+            let dialer = dial.build()?;
+
+            *quotas = Quotas::new(&quota, quota_file.as_deref(), quota_state.as_deref())?;
+
+            let config = socks::Config {
+                auth_info,
+                bind_info: socks::BindInfo::new(bind_ip, bind_ports),
+                rules: Rules::new(&rule, rules_file.as_deref())?,
+                routes: Routes::new(
+                    &route,
+                    routes_file.as_deref(),
+                    &dialer,
+                    agents,
+                    route_resolve,
+                )?,
+                dialer,
+                quotas: quotas.clone(),
+                lockout: Lockout::default(),
+                pipe: pipe.build()?,
+            };
+
+            let proxy = Proxy::new(
+                local_addrs,
+                remote_addr,
+                local_opts,
+                remote_opt,
+                protocol,
+                config,
+                ws.build()?,
+            );
             proxy.start().await?;
         }
         Commands::Reuse {
@@ -156,12 +506,58 @@ pub async fn run(cli: Cli) -> Result<()> {
             fallback,
             external,
             timeout,
+            dial,
+            pipe,
         } => {
             info!("Starting reuse mode");
 
-            let reuse = Reuse::new(local, remote, fallback, external, timeout);
+            let reuse = Reuse::new(
+                local,
+                remote,
+                fallback,
+                external,
+                timeout,
+                dial.build()?,
+                pipe.build()?,
+            );
             reuse.start().await?;
         }
+        #[cfg(target_os = "linux")]
+        Commands::Redir {
+            local,
+            control,
+            auth,
+            dial,
+            pipe,
+        } => {
+            info!("Starting redir mode");
+
+            let local_addr = match local.contains(':') {
+                true => local,
+                false => format!("0.0.0.0:{}", local),
+            };
+
+            let control_addr = control
+                .as_ref()
+                .map(|addr| addr.replace("+", ""))
+                .map(|addr| match addr.contains(':') {
+                    true => addr,
+                    false => format!("0.0.0.0:{}", addr),
+                });
+            let control_opt = control.is_some_and(|addr| addr.starts_with('+'));
+
+            let auth = auth.as_deref().map(util::parse_credentials).transpose()?;
+
+            let redir = Redir::new(
+                local_addr,
+                control_addr,
+                control_opt,
+                auth,
+                dial.build()?,
+                pipe.build()?,
+            );
+            redir.start().await?;
+        }
     }
 
     Ok(())
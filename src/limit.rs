@@ -0,0 +1,247 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{self, Instant};
+use tracing::info;
+
+use crate::util;
+
+/// Token bucket holding up to one second of traffic.
+///
+/// Bytes are taken after they have been transferred, the bucket may go into debt and the
+/// caller sleeps for the returned duration to pay it back.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` is in bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new(State {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes from the bucket, returns how long to wait before transferring more.
+    pub fn consume(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate) - n as f64;
+        state.last = now;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+/// Bandwidth limits of forwarded traffic, in bytes per second.
+///
+/// Each limit has the format `SCOPE [up=SIZE] [down=SIZE]`, where `SCOPE` is `global` (shared by
+/// all the traffic of the process), `listener` (shared by the connections of a listener) or
+/// `conn` (each connection or UDP session). `up` is the traffic from the client side to the
+/// target side, and `down` the traffic back.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    global: Buckets,
+    listener: Buckets,
+    listener_rates: Rates,
+    conn_rates: Rates,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Rates {
+    up: Option<u64>,
+    down: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+struct Buckets {
+    up: Option<Arc<TokenBucket>>,
+    down: Option<Arc<TokenBucket>>,
+}
+
+/// Buckets applied to a single connection, one limiter per direction.
+pub struct Throttle {
+    pub up: Limiter,
+    pub down: Limiter,
+}
+
+/// Every bucket a direction of a connection is charged to.
+pub struct Limiter {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl RateLimits {
+    pub fn new(limits: &[String]) -> Result<Self> {
+        let mut global = Rates::default();
+        let mut listener = Rates::default();
+        let mut conn = Rates::default();
+
+        for limit in limits {
+            let invalid = || {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid rate limit: {}", limit),
+                )
+            };
+
+            let mut fields = limit.split_whitespace();
+
+            let rates = match fields.next() {
+                Some("global") => &mut global,
+                Some("listener") => &mut listener,
+                Some("conn") => &mut conn,
+                _ => return Err(invalid()),
+            };
+
+            for field in fields {
+                let (direction, rate) = field.split_once('=').ok_or_else(invalid)?;
+
+                let rate = match util::parse_size(rate)? {
+                    0 => return Err(invalid()),
+                    rate => Some(rate),
+                };
+
+                match direction {
+                    "up" => rates.up = rate,
+                    "down" => rates.down = rate,
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+
+        if !limits.is_empty() {
+            info!("Load {} rate limits", limits.len());
+        }
+
+        Ok(Self {
+            global: Buckets::new(global),
+            listener: Buckets::default(),
+            listener_rates: listener,
+            conn_rates: conn,
+        })
+    }
+
+    /// Limits of a new listener, with buckets shared by all of its connections.
+    pub fn listener(&self) -> Self {
+        Self {
+            listener: Buckets::new(self.listener_rates),
+            ..self.clone()
+        }
+    }
+
+    /// Buckets of a new connection, charged to the listener and global buckets as well.
+    pub fn connection(&self) -> Throttle {
+        let conn = Buckets::new(self.conn_rates);
+        let scopes = [&self.global, &self.listener, &conn];
+
+        Throttle {
+            up: Limiter {
+                buckets: scopes.iter().filter_map(|b| b.up.clone()).collect(),
+            },
+            down: Limiter {
+                buckets: scopes.iter().filter_map(|b| b.down.clone()).collect(),
+            },
+        }
+    }
+}
+
+impl Buckets {
+    fn new(rates: Rates) -> Self {
+        Self {
+            up: rates.up.map(|rate| Arc::new(TokenBucket::new(rate))),
+            down: rates.down.map(|rate| Arc::new(TokenBucket::new(rate))),
+        }
+    }
+}
+
+impl Limiter {
+    /// Take `n` bytes from every bucket, returns how long to wait for the slowest one.
+    pub fn consume(&self, n: usize) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.consume(n))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Take `n` bytes from every bucket and wait until the slowest one allows more traffic.
+    pub async fn wait(&self, n: usize) {
+        let delay = self.consume(n);
+
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(lines: &[&str]) -> Result<RateLimits> {
+        let lines: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        RateLimits::new(&lines)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_debt_and_refill() {
+        let bucket = TokenBucket::new(1000);
+
+        // a full bucket lets one second of traffic through, then goes into debt
+        assert_eq!(bucket.consume(500), Duration::ZERO);
+        assert_eq!(bucket.consume(1000), Duration::from_millis(500));
+
+        // the debt is paid back over time
+        time::advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.consume(0), Duration::ZERO);
+
+        // and the bucket never holds more than one second of traffic
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.consume(1500), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limiter_waits_for_slowest_bucket() {
+        let limits = limits(&["global up=1000", "conn up=100 down=1000"]).unwrap();
+        let throttle = limits.listener().connection();
+
+        assert_eq!(throttle.up.consume(200), Duration::from_secs(1));
+        assert_eq!(throttle.down.consume(200), Duration::ZERO);
+
+        // connections without limits are not throttled
+        let throttle = RateLimits::default().connection();
+        assert_eq!(throttle.up.consume(1 << 30), Duration::ZERO);
+    }
+
+    #[test]
+    fn parse() {
+        assert!(limits(&["global up=1M down=512K", "listener down=1G", "conn up=10"]).is_ok());
+
+        assert!(limits(&["conn up=0"]).is_err());
+        assert!(limits(&["host up=1K"]).is_err());
+        assert!(limits(&["conn sideways=1K"]).is_err());
+        assert!(limits(&["conn up"]).is_err());
+        assert!(limits(&["conn up=fast"]).is_err());
+        assert!(limits(&[""]).is_err());
+    }
+}
@@ -9,6 +9,7 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let cli = Cli::parse();
 
+    // returns on ctrl-c and SIGTERM as well, once the pending state is written
     if let Err(e) = pivot::run(cli).await {
         error!("error: {}", e);
     }
@@ -1,23 +1,51 @@
-use std::{io::Result, sync::Arc};
+use std::{fmt, io::Cursor, io::Result, net::SocketAddr, sync::Arc, time::Duration};
 
+use clap::ValueEnum;
 use tokio::{
-    join,
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+    time,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
-    crypto,
-    socks::{handle_connection, AuthInfo},
-    tcp::{self},
+    http, socks,
+    tcp::{self, NetStream},
+    transport::{self, Listener},
+    util::Backoff,
+    ws,
 };
 
+/// Number of handshaked reverse connections waiting for a proxy client.
+const CONTROL_POOL_SIZE: usize = 32;
+
+/// Protocol spoken on the proxy port.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Protocol {
+    Socks,
+    Http,
+    /// Socks and HTTP on the same port
+    Mixed,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Socks => write!(f, "socks"),
+            Protocol::Http => write!(f, "http"),
+            Protocol::Mixed => write!(f, "mixed"),
+        }
+    }
+}
+
 pub struct Proxy {
     local_addrs: Vec<String>,
     remote_addr: Option<String>,
     local_opts: Vec<bool>,
     remote_opt: bool,
-    auth_info: Option<AuthInfo>,
+    protocol: Protocol,
+    config: socks::Config,
+    ws: ws::Config,
 }
 
 impl Proxy {
@@ -26,20 +54,24 @@ impl Proxy {
         remote_addr: Option<String>,
         local_opts: Vec<bool>,
         remote_opt: bool,
-        auth_info: Option<AuthInfo>,
+        protocol: Protocol,
+        config: socks::Config,
+        ws: ws::Config,
     ) -> Self {
         Self {
             local_addrs,
             remote_addr,
             local_opts,
             remote_opt,
-            auth_info,
+            protocol,
+            config,
+            ws,
         }
     }
 
     pub async fn start(&self) -> Result<()> {
         match (self.local_addrs.len(), &self.remote_addr) {
-            (1, None) => self.socks_server().await?,
+            (1, None) => self.proxy_server().await?,
             (2, None) => self.socks_reverse_server().await?,
             (0, Some(_)) => self.socks_reverse_client().await?,
             _ => error!("Invalid proxy parameters"),
@@ -48,26 +80,32 @@ impl Proxy {
         Ok(())
     }
 
-    async fn socks_server(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.local_addrs[0]).await?;
-        info!("Start socks server on {}", listener.local_addr()?);
-
-        let acceptor =
-            Arc::new(self.local_opts[0].then(|| crypto::get_tls_acceptor(&self.local_addrs[0])));
+    async fn proxy_server(&self) -> Result<()> {
+        let listener = transport::listen(&self.local_addrs[0], self.local_opts[0]).await?;
+        info!(
+            "Start {} proxy server on {}",
+            self.protocol,
+            listener.local_addr()?
+        );
 
-        let auth_info = Arc::new(self.auth_info.clone());
+        let config = Arc::new(socks::Config {
+            pipe: self.config.pipe.listener(&[listener.local_addr()?]),
+            ..self.config.clone()
+        });
+        let protocol = self.protocol;
 
         loop {
             let (stream, addr) = listener.accept().await?;
             info!("Accept connection from {}", addr);
 
-            let acceptor = acceptor.clone();
-            let auth_info = auth_info.clone();
+            let config = config.clone();
 
             tokio::spawn(async move {
-                let stream = tcp::NetStream::from_acceptor(stream, acceptor).await;
+                let Ok(stream) = stream.await else {
+                    return;
+                };
 
-                if let Err(e) = handle_connection(stream, auth_info.as_ref()).await {
+                if let Err(e) = handle_connection(protocol, stream, Some(addr), &config).await {
                     error!("Failed to handle connection: {}", e);
                 }
             });
@@ -75,28 +113,52 @@ impl Proxy {
     }
 
     async fn socks_reverse_client(&self) -> Result<()> {
-        let remote_addr = self.remote_addr.clone().unwrap();
-
-        let connector = Arc::new(self.remote_opt.then(|| crypto::get_tls_connector()));
-
-        let auth_info = Arc::new(self.auth_info.clone());
+        let remote = transport::dial(
+            self.remote_addr.as_ref().unwrap(),
+            self.remote_opt,
+            &self.config.dialer,
+            &self.ws,
+        )?;
+
+        let config = Arc::new(socks::Config {
+            pipe: self.config.pipe.listener(&[]),
+            ..self.config.clone()
+        });
+        let protocol = self.protocol;
 
         // limit the number of concurrent connections
         let semaphore = Arc::new(tokio::sync::Semaphore::new(32));
+        let mut backoff = Backoff::default();
 
         loop {
             let permit = semaphore.clone().acquire_owned().await;
 
-            let stream = TcpStream::connect(&remote_addr).await?;
-            info!("Connect to remote {} success", stream.peer_addr()?);
+            // keep the agent running while the reverse server is unreachable
+            let (stream, remote_addr) = match remote.connect().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "Failed to connect to remote {}: {}",
+                        self.remote_addr.as_ref().unwrap(),
+                        e
+                    );
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            backoff.succeeded();
+
+            info!("Connect to remote {} success", remote_addr);
 
-            let connector = connector.clone();
-            let auth_info = auth_info.clone();
+            let config = config.clone();
 
             tokio::spawn(async move {
-                let stream = tcp::NetStream::from_connector(stream, connector).await;
+                let Ok(stream) = stream.await else {
+                    return;
+                };
 
-                if let Err(e) = handle_connection(stream, auth_info.as_ref()).await {
+                // the clients are behind the reverse server, their addresses are unknown
+                if let Err(e) = handle_connection(protocol, stream, None, &config).await {
                     error!("Failed to handle connection: {}", e);
                 }
 
@@ -107,41 +169,153 @@ impl Proxy {
     }
 
     async fn socks_reverse_server(&self) -> Result<()> {
-        let control_listener = TcpListener::bind(&self.local_addrs[0]).await?;
-        let proxy_listener = TcpListener::bind(&self.local_addrs[1]).await?;
+        let control_listener = transport::listen(&self.local_addrs[0], self.local_opts[0]).await?;
+        let proxy_listener = transport::listen(&self.local_addrs[1], self.local_opts[1]).await?;
 
         info!("Bind to {} success", control_listener.local_addr()?);
         info!("Bind to {} success", proxy_listener.local_addr()?);
 
-        let control_acceptor =
-            Arc::new(self.local_opts[0].then(|| crypto::get_tls_acceptor(&self.local_addrs[0])));
-        let proxy_acceptor =
-            Arc::new(self.local_opts[1].then(|| crypto::get_tls_acceptor(&self.local_addrs[1])));
+        let pipe = self
+            .config
+            .pipe
+            .listener(&[control_listener.local_addr()?, proxy_listener.local_addr()?]);
+
+        let config = Arc::new(self.config.clone());
+        let mut controls = accept_controls(control_listener);
 
         loop {
-            let (r1, r2) = join!(proxy_listener.accept(), control_listener.accept());
+            let (proxy_stream, proxy_addr) = proxy_listener.accept().await?;
+            info!("Accept connection from {}", proxy_addr);
 
-            let (proxy_stream, proxy_addr) = r1?;
-            let (control_stream, control_addr) = r2?;
+            // skip the reverse connections which went away while waiting, e.g. by the idle
+            // timeout of the client
+            let (control_stream, control_addr) = loop {
+                let (mut stream, addr) = match controls.recv().await {
+                    Some(control) => control,
+                    None => return Ok(()),
+                };
+
+                if is_closed(&mut stream).await {
+                    warn!("Drop closed connection from {}", addr);
+                    continue;
+                }
 
-            info!("Accept connection from {}", proxy_addr);
-            info!("Accept connection from {}", control_addr);
+                break (stream, addr);
+            };
 
-            let proxy_acceptor = proxy_acceptor.clone();
-            let control_acceptor = control_acceptor.clone();
+            let config = config.clone();
+            let pipe = pipe.clone();
 
             tokio::spawn(async move {
-                let proxy_stream =
-                    tcp::NetStream::from_acceptor(proxy_stream, proxy_acceptor).await;
-                let control_stream =
-                    tcp::NetStream::from_acceptor(control_stream, control_acceptor).await;
+                let mut control_stream = control_stream;
+                let Some(proxy_stream) =
+                    transport::establish(proxy_stream, &mut control_stream, control_addr).await
+                else {
+                    return;
+                };
+
+                // UDP ASSOCIATE is served here, the clients can not reach the agents
+                let (proxy_stream, control_stream) = match socks::handle_reverse_udp(
+                    proxy_stream,
+                    proxy_addr,
+                    control_stream,
+                    &config,
+                )
+                .await
+                {
+                    Ok(Some(streams)) => streams,
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("Failed to handle connection: {}", e);
+                        return;
+                    }
+                };
 
                 info!("Open pipe: {} <=> {}", proxy_addr, control_addr);
-                if let Err(e) = tcp::handle_forward(proxy_stream, control_stream).await {
-                    error!("Failed to handle forward: {}", e);
+                let pipe = pipe.with_peers(proxy_addr, control_addr);
+                let stats = tcp::handle_forward(proxy_stream, control_stream, &pipe).await;
+                info!(
+                    "Close pipe: {} <=> {} ({})",
+                    proxy_addr, control_addr, stats
+                );
+            });
+        }
+    }
+}
+
+/// Accept the reverse connections in the background, they wait in the queue once their
+/// handshake is done.
+fn accept_controls(listener: Box<dyn Listener>) -> mpsc::Receiver<(NetStream, SocketAddr)> {
+    let (tx, rx) = mpsc::channel(CONTROL_POOL_SIZE);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
                 }
-                info!("Close pipe: {} <=> {}", proxy_addr, control_addr);
+            };
+
+            info!("Accept connection from {}", addr);
+
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                let Ok(stream) = stream.await else {
+                    return;
+                };
+
+                // waits while the queue is full, the client dials again once one is used
+                let _ = tx.send((stream, addr)).await;
             });
         }
+    });
+
+    rx
+}
+
+/// Whether a waiting reverse connection has been closed, the proxy client always speaks first
+/// so that anything to read means the connection is gone.
+async fn is_closed(stream: &mut NetStream) -> bool {
+    let mut buf = [0u8; 1];
+    time::timeout(Duration::ZERO, stream.read(&mut buf))
+        .await
+        .is_ok()
+}
+
+async fn handle_connection(
+    protocol: Protocol,
+    stream: NetStream,
+    peer_addr: Option<SocketAddr>,
+    config: &socks::Config,
+) -> Result<()> {
+    match protocol {
+        Protocol::Socks => socks::handle_connection(stream, peer_addr, config).await,
+        Protocol::Http => http::handle_connection(stream, peer_addr, config).await,
+        Protocol::Mixed => handle_mixed(stream, peer_addr, config).await,
+    }
+}
+
+/// Sniff the first byte to tell socks clients from HTTP clients, then hand the byte back to the handler.
+///
+/// The byte is read instead of peeked so that TLS streams can be sniffed as well.
+async fn handle_mixed(
+    stream: NetStream,
+    peer_addr: Option<SocketAddr>,
+    config: &socks::Config,
+) -> Result<()> {
+    let local_addr = stream.local_addr().ok();
+    let (mut reader, writer) = stream.split();
+
+    let first = config.handshake(reader.read_u8()).await?;
+    let reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new([first]).chain(reader));
+
+    match first {
+        0x04 | 0x05 => {
+            socks::handle_connection_splitted(reader, writer, local_addr, peer_addr, config).await
+        }
+        _ => http::handle_connection_splitted(reader, writer, peer_addr, config).await,
     }
 }
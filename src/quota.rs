@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io::{Error, ErrorKind, Result},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
+use tracing::{error, info, warn};
+
+use crate::{limit::TokenBucket, util};
+
+/// How often the usage is written to the state file.
+const SAVE_INTERVAL: u64 = 30;
+
+/// Per-user limits of the proxy server, applied to authenticated users only.
+///
+/// Each quota has the format `USER [conns=N] [bytes=SIZE/PERIOD] [rate=SIZE]`, where `USER` is a
+/// user name or `*` for every user without a quota of their own, `conns` caps the concurrent
+/// connections, `bytes` the traffic per `hour`, `day`, `week` or `month` (30 days), and `rate`
+/// the bandwidth in bytes per second shared by the connections of the user.
+#[derive(Clone, Default)]
+pub struct Quotas {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    limits: HashMap<String, Limit>,
+    default: Option<Limit>,
+    users: Mutex<HashMap<String, Arc<Usage>>>,
+    state_file: Option<PathBuf>,
+    dirty: AtomicBool,
+}
+
+#[derive(Clone)]
+struct Limit {
+    conns: Option<usize>,
+    bytes: Option<(u64, Duration)>,
+    rate: Option<u64>,
+}
+
+struct Usage {
+    limit: Limit,
+    conns: AtomicUsize,
+    period: Mutex<Period>,
+    bucket: Option<TokenBucket>,
+}
+
+#[derive(Clone, Copy)]
+struct Period {
+    start: SystemTime,
+    used: u64,
+}
+
+/// Accounting of a connection, the connection slot is released on drop.
+pub struct QuotaGuard {
+    usage: Arc<Usage>,
+    inner: Arc<Inner>,
+}
+
+/// Stream wrapper which charges the transferred bytes to a user.
+pub struct Metered<S> {
+    inner: S,
+    usage: Arc<Usage>,
+    owner: Arc<Inner>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Quotas {
+    /// Load quotas from the command line, followed by the quotas in the file (one per line, `#` for comments).
+    ///
+    /// The usage of each period is restored from and saved to `state_file` across restarts.
+    pub fn new(quotas: &[String], file: Option<&str>, state_file: Option<&str>) -> Result<Self> {
+        let mut lines = quotas.to_vec();
+
+        if let Some(file) = file {
+            lines.extend(util::read_config_lines(file)?);
+        }
+
+        if lines.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut limits = HashMap::new();
+        let mut default = None;
+
+        for line in &lines {
+            let (user, limit) = parse_quota(line)?;
+
+            match user.as_str() {
+                "*" => default = Some(limit),
+                _ => {
+                    limits.insert(user, limit);
+                }
+            }
+        }
+
+        info!("Load {} user quotas", lines.len());
+
+        let inner = Arc::new(Inner {
+            limits,
+            default,
+            users: Mutex::new(HashMap::new()),
+            state_file: state_file.map(PathBuf::from),
+            dirty: AtomicBool::new(false),
+        });
+
+        if let Some(path) = &inner.state_file {
+            if path.exists() {
+                inner.load()?;
+            }
+
+            let inner = inner.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(SAVE_INTERVAL));
+
+                loop {
+                    interval.tick().await;
+
+                    if inner.dirty.swap(false, Ordering::Relaxed) {
+                        if let Err(e) = inner.save() {
+                            error!("Failed to save quota state: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(Self { inner: Some(inner) })
+    }
+
+    /// Write the usage since the last save to the state file, called on shutdown.
+    pub fn save(&self) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+
+        if inner.state_file.is_some() && inner.dirty.swap(false, Ordering::Relaxed) {
+            if let Err(e) = inner.save() {
+                error!("Failed to save quota state: {}", e);
+            }
+        }
+    }
+
+    /// Take a connection slot of the user, fails with `PermissionDenied` when a quota is exceeded.
+    pub fn acquire(&self, user: Option<&str>) -> Result<Option<QuotaGuard>> {
+        let (inner, user) = match (&self.inner, user) {
+            (Some(inner), Some(user)) => (inner, user),
+            _ => return Ok(None),
+        };
+
+        let usage = match inner.usage(user) {
+            Some(usage) => usage,
+            None => return Ok(None),
+        };
+
+        let exceeded = |reason: String| {
+            warn!("Quota exceeded for user {}: {}", user, reason);
+            Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Quota exceeded: {}", reason),
+            ))
+        };
+
+        if let Some(used) = usage.bytes_exceeded() {
+            return exceeded(format!("{} bytes transferred", used));
+        }
+
+        // reserve the slot first, so that concurrent requests can not overshoot
+        let conns = usage.conns.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if usage.limit.conns.is_some_and(|max| conns > max) {
+            usage.conns.fetch_sub(1, Ordering::SeqCst);
+            return exceeded(format!("{} concurrent connections", conns - 1));
+        }
+
+        Ok(Some(QuotaGuard {
+            usage,
+            inner: inner.clone(),
+        }))
+    }
+}
+
+impl QuotaGuard {
+    pub fn meter<S>(&self, stream: S) -> Metered<S> {
+        Metered {
+            inner: stream,
+            usage: self.usage.clone(),
+            owner: self.inner.clone(),
+            delay: None,
+        }
+    }
+
+    /// Charge a relayed datagram and wait for the bandwidth cap, fails with `PermissionDenied`
+    /// once the byte quota is used up, the datagram is dropped then.
+    pub async fn datagram(&self, n: usize) -> Result<()> {
+        if let Some(used) = self.usage.bytes_exceeded() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Quota exceeded: {} bytes transferred", used),
+            ));
+        }
+
+        let delay = self.usage.charge(n, &self.inner);
+
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        self.usage.conns.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Inner {
+    fn usage(&self, user: &str) -> Option<Arc<Usage>> {
+        let mut users = self.users.lock().unwrap();
+
+        if let Some(usage) = users.get(user) {
+            return Some(usage.clone());
+        }
+
+        let limit = self.limits.get(user).or(self.default.as_ref())?.clone();
+        let usage = Arc::new(Usage::new(limit, Period::new()));
+
+        users.insert(user.to_string(), usage.clone());
+        Some(usage)
+    }
+
+    /// Restore the byte usage, format: `user start used` per line with `start` in unix seconds.
+    fn load(&self) -> Result<()> {
+        let path = self.state_file.as_ref().unwrap();
+        let mut users = self.users.lock().unwrap();
+
+        for line in util::read_config_lines(&path.to_string_lossy())? {
+            let fields: Vec<_> = line.split_whitespace().collect();
+
+            let (user, start, used) = match fields[..] {
+                [user, start, used] => match (start.parse(), used.parse()) {
+                    (Ok(start), Ok(used)) => (user, start, used),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            // drop the users whose quota has been removed
+            let limit = match self.limits.get(user).or(self.default.as_ref()) {
+                Some(limit) => limit.clone(),
+                None => continue,
+            };
+
+            let period = Period {
+                start: UNIX_EPOCH + Duration::from_secs(start),
+                used,
+            };
+
+            users.insert(user.to_string(), Arc::new(Usage::new(limit, period)));
+        }
+
+        info!(
+            "Restore quota usage of {} users from {}",
+            users.len(),
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = self.state_file.as_ref().unwrap();
+
+        let mut content = String::new();
+
+        for (user, usage) in self.users.lock().unwrap().iter() {
+            let period = *usage.period.lock().unwrap();
+            let start = period
+                .start
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            content.push_str(&format!("{} {} {}\n", user, start, period.used));
+        }
+
+        // write to a temporary file first, the state is never left half written
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl Usage {
+    fn new(limit: Limit, period: Period) -> Self {
+        Self {
+            bucket: limit.rate.map(TokenBucket::new),
+            limit,
+            conns: AtomicUsize::new(0),
+            period: Mutex::new(period),
+        }
+    }
+
+    /// Returns the bytes used in the current period once the byte quota is used up.
+    fn bytes_exceeded(&self) -> Option<u64> {
+        let (max, period) = self.limit.bytes?;
+
+        let mut current = self.period.lock().unwrap();
+        current.renew(period);
+
+        (current.used >= max).then_some(current.used)
+    }
+
+    /// Charge transferred bytes, returns how long to wait for the bandwidth cap.
+    fn charge(&self, n: usize, owner: &Inner) -> Duration {
+        if let Some((_, period)) = self.limit.bytes {
+            let mut current = self.period.lock().unwrap();
+            current.renew(period);
+            current.used += n as u64;
+
+            owner.dirty.store(true, Ordering::Relaxed);
+        }
+
+        match &self.bucket {
+            Some(bucket) => bucket.consume(n),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Period {
+    fn new() -> Self {
+        Self {
+            start: SystemTime::now(),
+            used: 0,
+        }
+    }
+
+    /// Start a new period once the current one is over.
+    fn renew(&mut self, length: Duration) {
+        let now = SystemTime::now();
+
+        if now.duration_since(self.start).unwrap_or_default() >= length {
+            *self = Self::new();
+        }
+    }
+}
+
+impl<S> Metered<S> {
+    /// Cut the connection once the byte quota is used up, it may overshoot by one buffer.
+    fn check(&self) -> Result<()> {
+        match self.usage.bytes_exceeded() {
+            Some(used) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Quota exceeded: {} bytes transferred", used),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn charge(&mut self, n: usize) {
+        let delay = self.usage.charge(n, &self.owner);
+
+        if !delay.is_zero() {
+            self.delay = Some(Box::pin(time::sleep(delay)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+        this.check()?;
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        this.charge(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+        this.check()?;
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+        this.charge(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Parse `USER [conns=N] [bytes=SIZE/PERIOD] [rate=SIZE]`.
+fn parse_quota(s: &str) -> Result<(String, Limit)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid quota: {}", s));
+
+    let mut fields = s.split_whitespace();
+    let user = fields.next().ok_or_else(invalid)?.to_string();
+
+    let mut limit = Limit {
+        conns: None,
+        bytes: None,
+        rate: None,
+    };
+
+    for field in fields {
+        match field.split_once('=').ok_or_else(invalid)? {
+            ("conns", n) => limit.conns = Some(n.parse().map_err(|_| invalid())?),
+            ("bytes", bytes) => {
+                let (size, period) = bytes.split_once('/').ok_or_else(invalid)?;
+
+                let period = match period {
+                    "hour" => 3600,
+                    "day" => 86400,
+                    "week" => 7 * 86400,
+                    "month" => 30 * 86400,
+                    _ => return Err(invalid()),
+                };
+
+                limit.bytes = Some((util::parse_size(size)?, Duration::from_secs(period)));
+            }
+            ("rate", rate) => match util::parse_size(rate)? {
+                0 => return Err(invalid()),
+                rate => limit.rate = Some(rate),
+            },
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok((user, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn quotas(lines: &[&str], state_file: Option<&str>) -> Quotas {
+        let lines: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        Quotas::new(&lines, None, state_file).unwrap()
+    }
+
+    fn is_exceeded(quotas: &Quotas, user: &str) -> bool {
+        match quotas.acquire(Some(user)) {
+            Ok(_) => false,
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+                true
+            }
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let (user, limit) = parse_quota("alice conns=2 bytes=1K/day rate=512").unwrap();
+        assert_eq!(user, "alice");
+        assert_eq!(limit.conns, Some(2));
+        assert_eq!(limit.bytes, Some((1024, Duration::from_secs(86400))));
+        assert_eq!(limit.rate, Some(512));
+
+        for line in [
+            "",
+            "alice conns",
+            "alice conns=x",
+            "alice bytes=1K",
+            "alice bytes=1K/year",
+            "alice rate=0",
+            "alice speed=1K",
+        ] {
+            assert!(parse_quota(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn connection_cap() {
+        let quotas = quotas(&["alice conns=2", "* conns=1"], None);
+
+        let first = quotas.acquire(Some("alice")).unwrap();
+        let second = quotas.acquire(Some("alice")).unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(is_exceeded(&quotas, "alice"));
+
+        // the slot is released with the guard
+        drop(first);
+        assert!(quotas.acquire(Some("alice")).unwrap().is_some());
+
+        // the other users share the default quota, but not its connections
+        let bob = quotas.acquire(Some("bob")).unwrap();
+        assert!(is_exceeded(&quotas, "bob"));
+        assert!(quotas.acquire(Some("carol")).unwrap().is_some());
+        drop(bob);
+
+        // anonymous clients are not limited
+        assert!(quotas.acquire(None).unwrap().is_none());
+        assert!(Quotas::default().acquire(Some("alice")).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn byte_quota_and_period_rollover() {
+        let quotas = quotas(&["alice bytes=100/hour"], None);
+
+        let guard = quotas.acquire(Some("alice")).unwrap().unwrap();
+        let mut stream = guard.meter(Vec::new());
+        stream.write_all(&[0u8; 60]).await.unwrap();
+        assert!(!is_exceeded(&quotas, "alice"));
+
+        stream.write_all(&[0u8; 40]).await.unwrap();
+        assert!(is_exceeded(&quotas, "alice"));
+
+        // open connections are cut as well
+        let e = stream.write_all(&[0u8; 1]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(guard.datagram(1).await.is_err());
+
+        // the usage starts over with the next period
+        let usage = quotas.inner.as_ref().unwrap().usage("alice").unwrap();
+        usage.period.lock().unwrap().start -= Duration::from_secs(3600);
+        assert!(!is_exceeded(&quotas, "alice"));
+        assert!(guard.datagram(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn state_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("pivot-quota-{}.state", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        let first = quotas(&["alice bytes=100/day", "bob bytes=1K/day"], Some(path_str));
+        let alice = first.acquire(Some("alice")).unwrap().unwrap();
+        let bob = first.acquire(Some("bob")).unwrap().unwrap();
+        alice.datagram(100).await.unwrap();
+        bob.datagram(10).await.unwrap();
+        first.save();
+
+        // bob has no quota any more, only the usage of alice is restored
+        let second = quotas(&["alice bytes=100/day"], Some(path_str));
+        assert!(is_exceeded(&second, "alice"));
+
+        let users = second.inner.as_ref().unwrap().users.lock().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users["alice"].period.lock().unwrap().used, 100);
+        drop(users);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use socket2::SockRef;
+use tokio::{
+    join,
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info};
+
+use crate::{
+    dialer::Dialer,
+    socks::{self, TargetAddr},
+    tcp::{self, NetStream, PipeConfig},
+    transport,
+};
+
+/// Transparent proxy for connections redirected by iptables/nftables `REDIRECT`.
+///
+/// The original destination is recovered with `SO_ORIGINAL_DST`, then the connection is
+/// forwarded directly (or through the upstream proxy of the dialer), or through the
+/// `pivot proxy -r` agents connected to the control address.
+pub struct Redir {
+    local_addr: String,
+    control_addr: Option<String>,
+    control_opt: bool,
+    auth: Option<(String, String)>,
+    dialer: Dialer,
+    pipe: PipeConfig,
+}
+
+impl Redir {
+    pub fn new(
+        local_addr: String,
+        control_addr: Option<String>,
+        control_opt: bool,
+        auth: Option<(String, String)>,
+        dialer: Dialer,
+        pipe: PipeConfig,
+    ) -> Self {
+        Self {
+            local_addr,
+            control_addr,
+            control_opt,
+            auth,
+            dialer,
+            pipe,
+        }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        match &self.control_addr {
+            Some(_) => self.redir_reverse().await,
+            None => self.redir_direct().await,
+        }
+    }
+
+    async fn redir_direct(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.local_addr).await?;
+        info!("Bind to {} success", listener.local_addr()?);
+
+        let dialer = Arc::new(self.dialer.clone());
+
+        let pipe = self.pipe.listener(&[listener.local_addr()?]);
+
+        loop {
+            let (client_stream, client_addr) = listener.accept().await?;
+            info!("Accept connection from {}", client_addr);
+
+            let dialer = dialer.clone();
+            let pipe = pipe.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_direct(client_stream, client_addr, &dialer, &pipe).await {
+                    error!("Failed to redirect {}: {}", client_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn redir_reverse(&self) -> Result<()> {
+        let control_addr = self.control_addr.as_ref().unwrap();
+
+        let redir_listener = TcpListener::bind(&self.local_addr).await?;
+        let control_listener = transport::listen(control_addr, self.control_opt).await?;
+
+        info!("Bind to {} success", redir_listener.local_addr()?);
+        info!("Bind to {} success", control_listener.local_addr()?);
+
+        let auth = Arc::new(self.auth.clone());
+
+        let pipe = self
+            .pipe
+            .listener(&[redir_listener.local_addr()?, control_listener.local_addr()?]);
+
+        loop {
+            let (r1, r2) = join!(redir_listener.accept(), control_listener.accept());
+
+            let (client_stream, client_addr) = r1?;
+            let (control_stream, control_addr) = r2?;
+
+            info!("Accept connection from {}", client_addr);
+            info!("Accept connection from {}", control_addr);
+
+            let auth = auth.clone();
+            let pipe = pipe.clone();
+
+            tokio::spawn(async move {
+                let mut client_stream = client_stream;
+                let Some(control_stream) =
+                    transport::establish(control_stream, &mut client_stream, client_addr).await
+                else {
+                    return;
+                };
+
+                if let Err(e) =
+                    handle_reverse(client_stream, client_addr, control_stream, &auth, &pipe).await
+                {
+                    error!("Failed to redirect {}: {}", client_addr, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_direct(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    dialer: &Dialer,
+    pipe: &PipeConfig,
+) -> Result<()> {
+    let target = original_dst(&stream)?;
+    let remote_stream = dialer.connect(&TargetAddr::Ip(target)).await?;
+
+    info!("Open pipe: {} <=> {}", client_addr, target);
+    let pipe = pipe.with_peers(client_addr, target);
+    let stats = tcp::handle_forward(stream.into(), remote_stream.into(), &pipe).await;
+    info!("Close pipe: {} <=> {} ({})", client_addr, target, stats);
+
+    Ok(())
+}
+
+/// Ask the agent on the other side of the control connection to connect to the original destination.
+async fn handle_reverse(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    control_stream: NetStream,
+    auth: &Option<(String, String)>,
+    pipe: &PipeConfig,
+) -> Result<()> {
+    let target = original_dst(&stream)?;
+    let mut agent = control_stream;
+
+    let auth = auth
+        .as_ref()
+        .map(|(user, pass)| (user.as_str(), pass.as_str()));
+
+    socks::connect_through(&mut agent, &TargetAddr::Ip(target), auth).await?;
+
+    info!("Open pipe: {} <=> {}", client_addr, target);
+    let pipe = pipe.with_peers(client_addr, target);
+    let stats = tcp::handle_forward(stream.into(), agent, &pipe).await;
+    info!("Close pipe: {} <=> {} ({})", client_addr, target, stats);
+
+    Ok(())
+}
+
+/// Recover the destination of a redirected connection, see `SO_ORIGINAL_DST` in netfilter.
+fn original_dst(stream: &TcpStream) -> Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+    let socket = SockRef::from(stream);
+
+    let addr = match local_addr {
+        SocketAddr::V4(_) => socket.original_dst(),
+        SocketAddr::V6(_) => socket.original_dst_ipv6(),
+    }
+    .map_err(|e| match e.kind() {
+        // netfilter has no conntrack entry for the connection
+        ErrorKind::NotFound => Error::new(
+            ErrorKind::InvalidInput,
+            "Connection has not been redirected",
+        ),
+        _ => e,
+    })?;
+
+    let addr = addr.as_socket().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Original destination is not an IP address",
+        )
+    })?;
+
+    // the destination of a connection which has not been redirected is the listener itself
+    if addr == local_addr {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Connection has not been redirected",
+        ));
+    }
+
+    Ok(addr)
+}
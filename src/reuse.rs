@@ -1,14 +1,14 @@
 use std::{io::Result, net::SocketAddr};
 
 use socket2::{Domain, Protocol, Socket, Type};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
-    time,
-};
+use tokio::{net::TcpListener, sync::mpsc, time};
 use tracing::{error, info, warn};
 
-use crate::tcp;
+use crate::{
+    dialer::Dialer,
+    socks::TargetAddr,
+    tcp::{self, PipeConfig},
+};
 
 pub struct Reuse {
     local_addr: String,
@@ -16,6 +16,8 @@ pub struct Reuse {
     fallback_addr: Option<String>,
     external_ip: String,
     timeout: Option<u64>,
+    dialer: Dialer,
+    pipe: PipeConfig,
 }
 
 impl Reuse {
@@ -25,6 +27,8 @@ impl Reuse {
         fallback_addr: Option<String>,
         external_ip: String,
         timeout: Option<u64>,
+        dialer: Dialer,
+        pipe: PipeConfig,
     ) -> Self {
         Self {
             local_addr,
@@ -32,6 +36,8 @@ impl Reuse {
             fallback_addr,
             external_ip,
             timeout,
+            dialer,
+            pipe,
         }
     }
 
@@ -42,6 +48,11 @@ impl Reuse {
     async fn reuse_tcp(&self) -> Result<()> {
         let local_addr: SocketAddr = self.local_addr.parse().unwrap();
 
+        // fail at startup, the connections are redirected to these addresses from the loop below
+        let remote_target: TargetAddr = self.remote_addr.parse()?;
+        let fallback_target: Option<TargetAddr> =
+            self.fallback_addr.as_deref().map(str::parse).transpose()?;
+
         let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
         socket.set_reuse_address(true)?;
 
@@ -59,10 +70,13 @@ impl Reuse {
             info!("Bind to {} success", local_addr);
 
             loop {
-                let (client_stream, client_addr) = listener
-                    .accept()
-                    .await
-                    .expect("Failed to accept connection");
+                let (client_stream, client_addr) = match listener.accept().await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
 
                 info!("Accepted connection from: {}", client_addr);
                 tx.send((client_stream, client_addr)).await.unwrap();
@@ -83,12 +97,14 @@ impl Reuse {
 
         let mut alive_tasks = Vec::new();
 
+        let pipe = self.pipe.listener(&[local_addr]);
+
         while let Some((client_stream, client_addr)) = rx.recv().await {
             let server_addr = if client_addr.ip().to_string() == self.external_ip {
-                info!("Redirecting connection to {}", &self.remote_addr);
-                &self.remote_addr
+                info!("Redirecting connection to {}", remote_target);
+                &remote_target
             } else {
-                match &self.fallback_addr {
+                match &fallback_target {
                     Some(fallback_addr) => {
                         warn!("Invalid external IP, fallback to {}", fallback_addr);
                         fallback_addr
@@ -100,21 +116,35 @@ impl Reuse {
                 }
             };
 
-            let server_stream = TcpStream::connect(&server_addr)
-                .await
-                .expect(&format!("Failed to connect to {}", server_addr));
+            // the client connection is dropped, the listener keeps serving the others
+            let server_stream = match self.dialer.connect(server_addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to connect to {}: {}", server_addr, e);
+                    continue;
+                }
+            };
 
             info!("Connect to {} success", server_addr);
 
+            let remote_addr = match server_stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("Failed to get the address of {}: {}", server_addr, e);
+                    continue;
+                }
+            };
+
+            let pipe = pipe.clone();
+
             let task = tokio::spawn(async move {
-                let client_stream = tcp::NetStream::Tcp(client_stream);
-                let remote_stream = tcp::NetStream::Tcp(server_stream);
+                let client_stream = tcp::NetStream::from(client_stream);
+                let remote_stream = tcp::NetStream::from(server_stream);
 
                 info!("Open pipe: {} <=> {}", client_addr, local_addr);
-                if let Err(e) = tcp::handle_forward(client_stream, remote_stream).await {
-                    error!("Failed to forward: {}", e)
-                }
-                info!("Close pipe: {} <=> {}", client_addr, local_addr);
+                let pipe = pipe.with_peers(client_addr, remote_addr);
+                let stats = tcp::handle_forward(client_stream, remote_stream, &pipe).await;
+                info!("Close pipe: {} <=> {} ({})", client_addr, local_addr, stats);
             });
 
             alive_tasks.push(task);
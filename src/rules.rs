@@ -0,0 +1,307 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+
+use crate::{socks::TargetAddr, util};
+
+/// Destination access-control rules, evaluated in order and the first matching rule wins.
+///
+/// Each rule has the format `allow|deny DEST [PORTS] [USER]`, where `DEST` is `*`, an IP
+/// address, a CIDR or a domain glob (e.g. `*.corp.local`), `PORTS` is `*` or a comma
+/// separated list of ports and ranges, and `USER` is `*` or a user name.
+/// Destinations which do not match any rule are allowed.
+#[derive(Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone)]
+struct Rule {
+    action: Action,
+    matcher: Matcher,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+/// Destination pattern shared by the access-control rules and the routing table.
+#[derive(Clone)]
+pub struct Matcher {
+    dest: Dest,
+    ports: Option<Vec<RangeInclusive<u16>>>,
+    user: Option<String>,
+}
+
+#[derive(Clone)]
+enum Dest {
+    Any,
+    Net(IpNet),
+    Domain(String),
+}
+
+impl Rules {
+    /// Load rules from the command line, followed by the rules in the file (one per line, `#` for comments).
+    pub fn new(rules: &[String], file: Option<&str>) -> Result<Self> {
+        let mut lines = rules.to_vec();
+
+        if let Some(file) = file {
+            lines.extend(util::read_config_lines(file)?);
+        }
+
+        let rules = lines
+            .iter()
+            .map(|line| line.parse())
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `domain` is the requested name when the address has been resolved from a domain target.
+    pub fn is_allowed(&self, user: Option<&str>, domain: Option<&str>, addr: SocketAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher.matches(user, domain, addr))
+            .is_none_or(|rule| rule.action == Action::Allow)
+    }
+
+    /// Same as `is_allowed` for a target which is resolved on the far side of the connection.
+    ///
+    /// A domain target may resolve into the network of a deny rule, so it is refused when such a
+    /// rule comes before the first matching rule.
+    pub fn is_allowed_target(&self, user: Option<&str>, target: &TargetAddr) -> bool {
+        for rule in &self.rules {
+            if rule.matcher.matches_target(user, target) {
+                return rule.action == Action::Allow;
+            }
+
+            if rule.action == Action::Deny && rule.matcher.may_resolve_into(user, target) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (action, rest) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| invalid(s))?;
+
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(invalid(s)),
+        };
+
+        Ok(Self {
+            action,
+            matcher: rest.parse()?,
+        })
+    }
+}
+
+impl Matcher {
+    pub fn matches(&self, user: Option<&str>, domain: Option<&str>, addr: SocketAddr) -> bool {
+        let dest = match &self.dest {
+            Dest::Any => true,
+            Dest::Net(net) => net.contains(&addr.ip().to_canonical()),
+            Dest::Domain(pattern) => {
+                domain.is_some_and(|domain| util::glob_match(pattern, &domain.to_lowercase()))
+            }
+        };
+
+        dest && self.matches_port(addr.port()) && self.matches_user(user)
+    }
+
+    /// Match a target without resolving it, networks never match a domain target.
+    pub fn matches_target(&self, user: Option<&str>, target: &TargetAddr) -> bool {
+        match target {
+            TargetAddr::Ip(addr) => self.matches(user, None, *addr),
+            TargetAddr::Domain(domain, port) => {
+                let dest = match &self.dest {
+                    Dest::Any => true,
+                    Dest::Net(_) => false,
+                    Dest::Domain(pattern) => util::glob_match(pattern, &domain.to_lowercase()),
+                };
+
+                dest && self.matches_port(*port) && self.matches_user(user)
+            }
+        }
+    }
+
+    /// Whether the destination is a network, which only matches domain targets once resolved.
+    pub fn is_network(&self) -> bool {
+        matches!(self.dest, Dest::Net(_))
+    }
+
+    /// Whether a domain target could match the network of the pattern after resolution.
+    fn may_resolve_into(&self, user: Option<&str>, target: &TargetAddr) -> bool {
+        match target {
+            TargetAddr::Domain(_, port) => {
+                self.is_network() && self.matches_port(*port) && self.matches_user(user)
+            }
+            TargetAddr::Ip(_) => false,
+        }
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports
+            .as_ref()
+            .is_none_or(|ports| ports.iter().any(|range| range.contains(&port)))
+    }
+
+    fn matches_user(&self, user: Option<&str>) -> bool {
+        match &self.user {
+            Some(expected) => user == Some(expected.as_str()),
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Matcher {
+    type Err = Error;
+
+    /// Parse `DEST [PORTS] [USER]`.
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+
+        if fields.is_empty() || fields.len() > 3 {
+            return Err(invalid(s));
+        }
+
+        let dest = match fields[0] {
+            "*" => Dest::Any,
+            dest => match dest.parse::<IpNet>() {
+                Ok(net) => Dest::Net(net),
+                Err(_) => match dest.parse::<IpAddr>() {
+                    Ok(ip) => Dest::Net(IpNet::from(ip)),
+                    Err(_) => Dest::Domain(dest.to_lowercase()),
+                },
+            },
+        };
+
+        let ports = match fields.get(1) {
+            None | Some(&"*") => None,
+            Some(ports) => Some(
+                ports
+                    .split(',')
+                    .map(util::parse_port_range)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+        };
+
+        let user = match fields.get(2) {
+            None | Some(&"*") => None,
+            Some(user) => Some(user.to_string()),
+        };
+
+        Ok(Self { dest, ports, user })
+    }
+}
+
+fn invalid(s: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Invalid rule: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> Rules {
+        let lines: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        Rules::new(&lines, None).unwrap()
+    }
+
+    fn domain(name: &str, port: u16) -> TargetAddr {
+        TargetAddr::Domain(name.to_string(), port)
+    }
+
+    #[test]
+    fn parse() {
+        assert!(
+            rules(&[
+                "allow *",
+                "deny 10.0.0.0/8 22,80-90 alice",
+                "deny *.corp * *"
+            ])
+            .rules
+            .len()
+                == 3
+        );
+
+        for line in [
+            "",
+            "allow",
+            "reject *",
+            "deny * abc",
+            "deny * 1 alice extra",
+        ] {
+            assert!(line.parse::<Rule>().is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = rules(&["allow 10.1.0.0/16", "deny 10.0.0.0/8"]);
+
+        assert!(rules.is_allowed(None, None, "10.1.2.3:80".parse().unwrap()));
+        assert!(!rules.is_allowed(None, None, "10.2.0.1:80".parse().unwrap()));
+        assert!(rules.is_allowed(None, None, "192.168.0.1:80".parse().unwrap()));
+        assert!(!rules.is_allowed(None, None, "[::ffff:10.2.0.1]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn ports_and_users() {
+        let rules = rules(&["deny * 22,8000-8100 alice"]);
+        let addr = |port| SocketAddr::new("1.2.3.4".parse().unwrap(), port);
+
+        assert!(!rules.is_allowed(Some("alice"), None, addr(22)));
+        assert!(!rules.is_allowed(Some("alice"), None, addr(8050)));
+        assert!(rules.is_allowed(Some("alice"), None, addr(80)));
+        assert!(rules.is_allowed(Some("bob"), None, addr(22)));
+        assert!(rules.is_allowed(None, None, addr(22)));
+    }
+
+    #[test]
+    fn resolved_domain() {
+        let rules = rules(&["deny *.Corp.local", "deny 10.0.0.0/8"]);
+        let addr = "192.168.0.1:443".parse().unwrap();
+
+        assert!(!rules.is_allowed(None, Some("db.corp.local"), addr));
+        assert!(!rules.is_allowed(None, Some("internal"), "10.0.0.1:443".parse().unwrap()));
+        assert!(rules.is_allowed(None, Some("example.com"), addr));
+    }
+
+    #[test]
+    fn domain_target_against_network() {
+        // the name may resolve into the denied network on the far side
+        let rules = rules(&["allow *.example.com", "deny 10.0.0.0/8 22", "allow *"]);
+
+        assert!(rules.is_allowed_target(None, &domain("www.example.com", 22)));
+        assert!(!rules.is_allowed_target(None, &domain("internal", 22)));
+        assert!(rules.is_allowed_target(None, &domain("internal", 80)));
+        assert!(!rules.is_allowed_target(None, &TargetAddr::Ip("10.0.0.1:22".parse().unwrap())));
+        assert!(rules.is_allowed_target(None, &TargetAddr::Ip("10.0.0.1:80".parse().unwrap())));
+
+        // allowed networks do not open domain targets
+        let rules = self::rules(&["allow 10.0.0.0/8", "deny *"]);
+        assert!(!rules.is_allowed_target(None, &domain("internal", 80)));
+    }
+}
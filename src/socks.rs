@@ -1,187 +1,1919 @@
-use std::io::{Error, Result};
+use std::{
+    collections::HashSet,
+    fmt,
+    future::{self, Future},
+    io::{Cursor, Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
+use subtle::ConstantTimeEq;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    select, time,
 };
-use tracing::info;
+use tracing::{error, info, warn};
 
 use crate::{
-    tcp::{self, NetStream},
+    auth::{CredentialFile, Lockout, Source},
+    capture,
+    dialer::Dialer,
+    dns::Resolver,
+    quota::{QuotaGuard, Quotas},
+    route::{Route, Routes},
+    rules::Rules,
+    tcp::{self, NetStream, PipeConfig},
     util,
 };
 
+const UDP_BUFFER_SIZE: usize = 65535;
+
+/// Address sent in failure replies, where BND.ADDR has no meaning.
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// How long a BIND request waits for the incoming connection.
+const BIND_TIMEOUT: u64 = 120;
+
+/// How much data sent by the client before the second BIND reply is kept for the peer.
+const BIND_EARLY_DATA_SIZE: usize = 65536;
+
+#[derive(Clone, Default)]
+pub struct Config {
+    pub auth_info: Option<AuthInfo>,
+    pub bind_info: BindInfo,
+    pub rules: Rules,
+    pub dialer: Dialer,
+    pub quotas: Quotas,
+    pub lockout: Lockout,
+    pub routes: Routes,
+    pub pipe: PipeConfig,
+}
+
+impl Config {
+    /// Check the credentials of a client, sources failing repeatedly are delayed and locked out.
+    ///
+    /// `peer` is unknown in reverse mode, where every client comes through the same tunnel,
+    /// the failures are counted per user name there.
+    pub async fn authenticate(
+        &self,
+        auth: &AuthInfo,
+        peer: Option<SocketAddr>,
+        user: &[u8],
+        pass: &[u8],
+    ) -> bool {
+        let name = String::from_utf8_lossy(user);
+        let client = peer.map_or("-".to_string(), |peer| peer.to_string());
+        let source = match peer {
+            Some(peer) => Source::Ip(peer.ip().to_canonical()),
+            None => Source::User(name.to_string()),
+        };
+
+        if !self.lockout.attempt(&source) {
+            warn!("Reject user {} from locked out {}", name, client);
+            return false;
+        }
+
+        if auth.verify(user, pass).await {
+            self.lockout.succeeded(&source);
+            return true;
+        }
+
+        warn!("Authentication failed for user {} from {}", name, client);
+        self.lockout.failed(&source).await;
+
+        false
+    }
+
+    /// Config of the pipe from the client to the target, for the capture.
+    ///
+    /// Domain targets are recorded with the address the target stream is connected to.
+    pub fn pipe(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        addr: &TargetAddr,
+        target: &NetStream,
+    ) -> PipeConfig {
+        let target_addr = match addr {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(_, port) => target
+                .peer_addr()
+                .unwrap_or(SocketAddr::new(UNSPECIFIED_ADDR.ip(), *port)),
+        };
+
+        self.pipe
+            .with_peers(peer_addr.unwrap_or(capture::UNKNOWN_ADDR), target_addr)
+    }
+
+    /// Run a step of the protocol handshake, bounded by the idle timeout of the pipes so that
+    /// silent clients (or idle reverse connections) do not hold the task forever.
+    pub async fn handshake<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        match self.pipe.idle_timeout {
+            Some(timeout) => time::timeout(timeout, future)
+                .await
+                .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "Handshake timed out"))),
+            None => future.await,
+        }
+    }
+
+    /// Forward between the client and the target, the pipe is logged with its traffic.
+    pub async fn forward(
+        &self,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+        writer: Box<dyn AsyncWrite + Unpin + Send>,
+        peer_addr: Option<SocketAddr>,
+        addr: &TargetAddr,
+        target: NetStream,
+    ) {
+        let pipe = self.pipe(peer_addr, addr, &target);
+        let client = peer_addr.map_or("-".to_string(), |peer| peer.to_string());
+
+        info!("Open pipe: {} <=> {}", client, addr);
+        let stats = tcp::handle_forward_splitted(reader, writer, target, &pipe).await;
+        info!("Close pipe: {} <=> {} ({})", client, addr, stats);
+    }
+
+    /// Pick the route of the target, then resolve it, drop the addresses denied by the rules
+    /// and connect to the remaining ones.
+    pub async fn connect(&self, addr: &TargetAddr, user: Option<&str>) -> Result<NetStream> {
+        let dialer = match self.routes.select(user, addr, self.dialer.resolver()).await {
+            None => &self.dialer,
+            Some(route) => {
+                info!("Route {} via {}", addr, route);
+
+                match route {
+                    Route::Direct(dialer) | Route::Upstream(_, dialer) => dialer,
+                    Route::Agent(agents) => {
+                        // the agent resolves the domain on its side, see `is_allowed_target`
+                        if !self.rules.is_allowed_target(user, addr) {
+                            return Err(deny(addr, user));
+                        }
+                        return agents.connect(addr).await;
+                    }
+                    Route::Block => {
+                        warn!(
+                            "Block connection to {} for user {}",
+                            addr,
+                            user.unwrap_or("-")
+                        );
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Connection blocked by routing table",
+                        ));
+                    }
+                }
+            }
+        };
+
+        // without rules the upstream proxy may resolve the domain itself
+        if self.rules.is_empty() {
+            return dialer.connect(addr).await.map(NetStream::from);
+        }
+
+        // the upstream proxy resolves the domain on its side, keep the name off the local resolver
+        if dialer.has_upstream() {
+            if !self.rules.is_allowed_target(user, addr) {
+                return Err(deny(addr, user));
+            }
+            return dialer.connect(addr).await.map(NetStream::from);
+        }
+
+        let (domain, addrs): (_, Vec<_>) = match addr {
+            TargetAddr::Ip(addr) => (None, vec![*addr]),
+            TargetAddr::Domain(domain, port) => (
+                Some(domain.as_str()),
+                dialer.resolver().lookup(domain, *port).await?,
+            ),
+        };
+
+        let allowed: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| self.rules.is_allowed(user, domain, *addr))
+            .collect();
+
+        if allowed.is_empty() {
+            return Err(deny(addr, user));
+        }
+
+        dialer
+            .connect_resolved(addr, &allowed)
+            .await
+            .map(NetStream::from)
+    }
+}
+
+fn deny(addr: &TargetAddr, user: Option<&str>) -> Error {
+    warn!(
+        "Deny connection to {} for user {}",
+        addr,
+        user.unwrap_or("-")
+    );
+    Error::new(
+        ErrorKind::PermissionDenied,
+        "Connection not allowed by ruleset",
+    )
+}
+
+/// Where the listening socket of a BIND request is opened.
+#[derive(Clone)]
+pub struct BindInfo {
+    ip: Option<IpAddr>,
+    ports: RangeInclusive<u16>,
+}
+
+impl BindInfo {
+    pub fn new(ip: Option<IpAddr>, ports: Option<RangeInclusive<u16>>) -> Self {
+        Self {
+            ip,
+            ports: ports.unwrap_or(0..=0),
+        }
+    }
+
+    async fn listen(&self, local_addr: Option<SocketAddr>) -> Result<TcpListener> {
+        // fallback to the interface which accepted the control connection
+        let ip = match (self.ip, local_addr) {
+            (Some(ip), _) => ip,
+            (None, Some(addr)) => addr.ip(),
+            (None, None) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "No interface to listen on, use --bind-ip",
+                ))
+            }
+        };
+
+        let mut last_err = None;
+
+        for port in self.ports.clone() {
+            match TcpListener::bind(SocketAddr::new(ip, port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "Empty bind port range")))
+    }
+}
+
+impl Default for BindInfo {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
 #[derive(Clone)]
-pub struct AuthInfo {
-    user: String,
-    pass: String,
+pub enum AuthInfo {
+    Single { user: String, pass: String },
+    File(Arc<CredentialFile>),
 }
 
 impl AuthInfo {
     pub fn new(s: String) -> Self {
         let (user, pass) = if s.contains(':') {
             let (r1, r2) = s.split_once(':').unwrap();
+            info!("user: {}", r1);
+
             (r1.to_string(), r2.to_string())
         } else {
-            (
-                util::generate_random_string(12),
-                util::generate_random_string(12),
-            )
+            let user = util::generate_random_string(12);
+            let pass = util::generate_random_string(12);
+
+            // the generated password can not be known otherwise
+            info!("user: {} pass: {}", user, pass);
+
+            (user, pass)
         };
 
-        info!("user: {} pass: {}", user, pass);
+        Self::Single { user, pass }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Ok(Self::File(Arc::new(CredentialFile::new(path)?)))
+    }
+
+    pub async fn verify(&self, user: &[u8], pass: &[u8]) -> bool {
+        match self {
+            AuthInfo::Single {
+                user: expected_user,
+                pass: expected_pass,
+            } => {
+                // evaluate both comparisons, a short circuit would tell which one failed
+                (user.ct_eq(expected_user.as_bytes()) & pass.ct_eq(expected_pass.as_bytes())).into()
+            }
+            AuthInfo::File(file) => match (str::from_utf8(user), str::from_utf8(pass)) {
+                (Ok(user), Ok(pass)) => file.verify(user, pass).await,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Destination address carried by a SOCKS5 request or UDP datagram header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Resolve the target into a socket address.
+    ///
+    /// Addresses of the same family as `prefer` are picked first when a domain has both A and AAAA records.
+    pub async fn resolve(&self, resolver: &Resolver, prefer: SocketAddr) -> Result<SocketAddr> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(*addr),
+            TargetAddr::Domain(domain, port) => {
+                let addrs = resolver.lookup(domain, *port).await?;
+
+                addrs
+                    .iter()
+                    .find(|addr| addr.is_ipv4() == prefer.is_ipv4())
+                    .or(addrs.first())
+                    .copied()
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "Domain resolved to no address"))
+            }
+        }
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(0x01);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(0x04);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Domain(domain, port) => {
+                buf.push(0x03);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+    }
+}
+
+impl FromStr for TargetAddr {
+    type Err = Error;
+
+    /// Parse `host:port`, IPv6 literals must be enclosed in brackets.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid address {}", s));
 
-        Self { user, pass }
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+
+        if host.is_empty() || host.contains(':') {
+            return Err(invalid());
+        }
+
+        Ok(TargetAddr::Domain(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+pub async fn handle_connection(
+    stream: NetStream,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<()> {
+    // only BIND and UDP ASSOCIATE need it, transports without an IP address serve the rest
+    let local_addr = stream.local_addr().ok();
+    let (reader, writer) = stream.split();
+
+    handle_connection_splitted(reader, writer, local_addr, peer_addr, config).await
+}
+
+/// `local_addr` is the address which accepted the client, used in BIND and UDP ASSOCIATE replies,
+/// `None` when the transport has no IP address, and `peer_addr` the address of the client when it
+/// is known.
+pub async fn handle_connection_splitted(
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<()> {
+    // dispatch on the protocol version
+    match config.handshake(reader.read_u8()).await? {
+        0x05 => handle_socks5(reader, writer, local_addr, peer_addr, config).await,
+        0x04 => handle_socks4(reader, writer, peer_addr, config).await,
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid SOCKS protocol version",
+        )),
     }
 }
 
-pub async fn handle_connection(stream: NetStream, auth_info: &Option<AuthInfo>) -> Result<()> {
-    let (mut reader, mut writer) = stream.split();
+async fn handle_socks5(
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<()> {
+    let (user, header, addr) = config
+        .handshake(async {
+            // 1. auth negotiation
+            let nmethods = reader.read_u8().await? as usize;
+            let mut methods = vec![0u8; nmethods];
+            reader.read_exact(&mut methods).await?;
+
+            let user = match &config.auth_info {
+                Some(auth) => {
+                    // check username and password authentication
+                    if !methods.contains(&0x02) {
+                        writer.write_all(&[0x05, 0xff]).await?;
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "No supported authentication method",
+                        ));
+                    }
+
+                    writer.write_all(&[0x05, 0x02]).await?;
+
+                    let mut auth_buf = [0u8; 2];
+                    reader.read_exact(&mut auth_buf).await?;
+
+                    if auth_buf[0] != 0x01 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Invalid authentication version",
+                        ));
+                    }
+
+                    // read username
+                    let ulen = auth_buf[1] as usize;
+                    let mut username = vec![0u8; ulen];
+                    reader.read_exact(&mut username).await?;
+
+                    // read password
+                    let plen = reader.read_u8().await? as usize;
+                    let mut password = vec![0u8; plen];
+                    reader.read_exact(&mut password).await?;
+
+                    // check username and password
+                    if config
+                        .authenticate(auth, peer_addr, &username, &password)
+                        .await
+                    {
+                        writer.write_all(&[0x01, 0x00]).await?;
+                        Some(String::from_utf8_lossy(&username).to_string())
+                    } else {
+                        writer.write_all(&[0x01, 0x01]).await?;
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Authentication failed",
+                        ));
+                    }
+                }
+                None => {
+                    // no auth required
+                    writer.write_all(&[0x05, 0x00]).await?;
+                    None
+                }
+            };
+
+            // 2. handle request
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+
+            if header[0] != 0x05 {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 request"));
+            }
+
+            let addr = match read_addr(&mut reader, header[3]).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    // the rest of the request can not be parsed without knowing the address type
+                    if e.kind() == ErrorKind::Unsupported {
+                        write_reply(&mut writer, 0x08, UNSPECIFIED_ADDR).await?;
+                    }
+                    return Err(e);
+                }
+            };
+
+            Ok((user, header, addr))
+        })
+        .await?;
+
+    // 3. check user quotas, the guard is held until the request is done
+    let guard = match config.quotas.acquire(user.as_deref()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            write_reply(&mut writer, 0x02, UNSPECIFIED_ADDR).await?;
+            return Err(e);
+        }
+    };
+
+    let (reader, mut writer): (
+        Box<dyn AsyncRead + Unpin + Send>,
+        Box<dyn AsyncWrite + Unpin + Send>,
+    ) = match &guard {
+        Some(guard) => (Box::new(guard.meter(reader)), Box::new(guard.meter(writer))),
+        None => (reader, writer),
+    };
+
+    match header[1] {
+        0x01 => handle_connect(reader, writer, addr, peer_addr, user, config).await,
+        0x02 => handle_bind(reader, writer, addr, local_addr, peer_addr, user, config).await,
+        0x03 => {
+            let guard = guard.as_ref();
+            handle_udp_associate(
+                reader, writer, addr, local_addr, peer_addr, user, guard, config,
+            )
+            .await
+        }
+        _ => {
+            write_reply(&mut writer, 0x07, UNSPECIFIED_ADDR).await?;
+            Err(Error::new(ErrorKind::Unsupported, "Unsupported command"))
+        }
+    }
+}
 
+/// Ask a SOCKS5 server to CONNECT to `target` over an established stream, used to chain upstream proxies.
+pub async fn connect_through<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    stream: &mut S,
+    target: &TargetAddr,
+    auth: Option<(&str, &str)>,
+) -> Result<()> {
     // 1. auth negotiation
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+
     let mut buf = [0u8; 2];
-    reader.read_exact(&mut buf).await?;
+    stream.read_exact(&mut buf).await?;
 
     if buf[0] != 0x05 {
         return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
+            ErrorKind::InvalidData,
             "Invalid SOCKS5 protocol version",
         ));
     }
 
-    let nmethods = buf[1] as usize;
-    let mut methods = vec![0u8; nmethods];
-    reader.read_exact(&mut methods).await?;
-
-    match auth_info {
-        Some(auth) => {
-            // check username and password authentication
-            if !methods.contains(&0x02) {
-                writer.write_all(&[0x05, 0xff]).await?;
-                return Err(Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "No supported authentication method",
-                ));
-            }
-
-            writer.write_all(&[0x05, 0x02]).await?;
-
-            let mut auth_buf = [0u8; 2];
-            reader.read_exact(&mut auth_buf).await?;
-
-            if auth_buf[0] != 0x01 {
+    match (buf[1], auth) {
+        (0x00, _) => {}
+        (0x02, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
                 return Err(Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid authentication version",
+                    ErrorKind::InvalidInput,
+                    "Username or password too long",
                 ));
             }
 
-            // read username
-            let ulen = auth_buf[1] as usize;
-            let mut username = vec![0u8; ulen];
-            reader.read_exact(&mut username).await?;
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
 
-            // read password
-            let plen = reader.read_u8().await? as usize;
-            let mut password = vec![0u8; plen];
-            reader.read_exact(&mut password).await?;
-
-            // check username and password
-            if String::from_utf8_lossy(&username) == auth.user
-                && String::from_utf8_lossy(&password) == auth.pass
-            {
-                writer.write_all(&[0x01, 0x00]).await?;
-            } else {
-                writer.write_all(&[0x01, 0x01]).await?;
+            stream.read_exact(&mut buf).await?;
+            if buf[1] != 0x00 {
                 return Err(Error::new(
-                    std::io::ErrorKind::PermissionDenied,
-                    "Authentication failed",
+                    ErrorKind::PermissionDenied,
+                    "Upstream authentication failed",
                 ));
             }
         }
-        None => {
-            // no auth required
-            writer.write_all(&[0x05, 0x00]).await?;
+        _ => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "No supported authentication method",
+            ))
+        }
+    }
+
+    // 2. send request
+    if let TargetAddr::Domain(domain, _) = target {
+        if domain.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Domain too long"));
         }
     }
 
-    // 2. handle request
+    let mut request = vec![0x05, 0x01, 0x00];
+    target.write_to(&mut request);
+    stream.write_all(&request).await?;
+
+    // 3. read reply
     let mut header = [0u8; 4];
-    reader.read_exact(&mut header).await?;
+    stream.read_exact(&mut header).await?;
+    read_addr(stream, header[3]).await?;
+
+    match header[1] {
+        0x00 => Ok(()),
+        rep => Err(reply_error(rep)),
+    }
+}
+
+/// Map the reply field of an upstream server back to an error, the inverse of `reply_code`.
+fn reply_error(rep: u8) -> Error {
+    match rep {
+        0x02 => Error::new(
+            ErrorKind::PermissionDenied,
+            "Connection not allowed by upstream ruleset",
+        ),
+        0x03 => Error::new(ErrorKind::NetworkUnreachable, "Network unreachable"),
+        0x04 => Error::new(ErrorKind::HostUnreachable, "Host unreachable"),
+        0x05 => Error::new(ErrorKind::ConnectionRefused, "Connection refused"),
+        0x06 => Error::new(ErrorKind::TimedOut, "TTL expired"),
+        0x07 => Error::new(ErrorKind::Unsupported, "Command not supported"),
+        0x08 => Error::new(ErrorKind::Unsupported, "Address type not supported"),
+        _ => Error::other("General SOCKS server failure"),
+    }
+}
+
+/// Map a connect error to the reply field defined in RFC 1928 section 6.
+fn reply_code(e: &Error) -> u8 {
+    match e.kind() {
+        ErrorKind::PermissionDenied => 0x02,
+        ErrorKind::NetworkUnreachable => 0x03,
+        // failed name resolution means the host can not be reached either
+        ErrorKind::HostUnreachable | ErrorKind::NotFound => 0x04,
+        ErrorKind::ConnectionRefused => 0x05,
+        ErrorKind::TimedOut => 0x06,
+        _ => 0x01,
+    }
+}
+
+async fn handle_socks4(
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<()> {
+    // 1. read request, the version byte has been consumed
+    let (cmd, user, addr) = config
+        .handshake(async {
+            let cmd = reader.read_u8().await?;
+            let port = reader.read_u16().await?;
+
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip).await?;
+
+            let user = read_null_terminated(&mut reader).await?;
+
+            // SOCKS4a uses 0.0.0.x (x != 0) to indicate that a domain follows the user id
+            let addr = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+                let domain = read_null_terminated(&mut reader).await?;
+                TargetAddr::Domain(domain, port)
+            } else {
+                TargetAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+            };
+
+            Ok((cmd, user, addr))
+        })
+        .await?;
 
-    if header[0] != 0x05 {
+    // SOCKS4 has no password, so it can not be used when authentication is enabled
+    if config.auth_info.is_some() {
+        writer.write_all(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0]).await?;
         return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid SOCKS5 request",
+            ErrorKind::PermissionDenied,
+            "SOCKS4 not allowed when authentication is enabled",
         ));
     }
 
-    if header[1] != 0x01 {
+    if cmd != 0x01 {
+        writer.write_all(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0]).await?;
         return Err(Error::new(
-            std::io::ErrorKind::Unsupported,
-            "Only CONNECT command supported",
+            ErrorKind::Unsupported,
+            "Only CONNECT command supported for SOCKS4",
         ));
     }
 
-    let addr = match header[3] {
-        0x01 => {
-            // IPv4
-            let mut addr = [0u8; 4];
-            reader.read_exact(&mut addr).await?;
-            let mut port = [0u8; 2];
-            reader.read_exact(&mut port).await?;
-            format!(
-                "{}.{}.{}.{}:{}",
-                addr[0],
-                addr[1],
-                addr[2],
-                addr[3],
-                u16::from_be_bytes(port)
-            )
+    info!("SOCKS4 connect to {} (user id: {})", addr, user);
+
+    // 2. connect to the target server, the user id is not authenticated so rules for any user apply
+    let target = match config.connect(&addr, None).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            writer.write_all(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0]).await?;
+            return Err(e);
         }
-        0x03 => {
-            // domain
-            let len = reader.read_u8().await? as usize;
-            let mut domain = vec![0u8; len];
-            reader.read_exact(&mut domain).await?;
-            let mut port = [0u8; 2];
-            reader.read_exact(&mut port).await?;
-            format!(
-                "{}:{}",
-                String::from_utf8_lossy(&domain),
-                u16::from_be_bytes(port)
-            )
+    };
+
+    // 3. send success response
+    writer.write_all(&[0x00, 0x5a, 0, 0, 0, 0, 0, 0]).await?;
+
+    // 4. forward data
+    config
+        .forward(reader, writer, peer_addr, &addr, target)
+        .await;
+
+    Ok(())
+}
+
+async fn read_null_terminated<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<String> {
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_u8().await? {
+            0x00 => break,
+            b if buf.len() < 255 => buf.push(b),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "SOCKS4 field too long")),
         }
-        0x04 => {
+    }
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+async fn handle_connect(
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    addr: TargetAddr,
+    peer_addr: Option<SocketAddr>,
+    user: Option<String>,
+    config: &Config,
+) -> Result<()> {
+    // 3. connect to the target server
+    let target = match config.connect(&addr, user.as_deref()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            write_reply(&mut writer, reply_code(&e), UNSPECIFIED_ADDR).await?;
+            return Err(e);
+        }
+    };
+
+    // 4. send success response with the local address of the outbound socket, some transports
+    // have none, the request still succeeded
+    let bind_addr = target.local_addr().unwrap_or(UNSPECIFIED_ADDR);
+    write_reply(&mut writer, 0x00, bind_addr).await?;
+
+    // 5. forward data
+    config
+        .forward(reader, writer, peer_addr, &addr, target)
+        .await;
+
+    Ok(())
+}
+
+async fn handle_bind(
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    peer: TargetAddr,
+    local_addr: Option<SocketAddr>,
+    client_addr: Option<SocketAddr>,
+    user: Option<String>,
+    config: &Config,
+) -> Result<()> {
+    // 3. open the listening socket
+    let listener = match config.bind_info.listen(local_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            write_reply(&mut writer, reply_code(&e), UNSPECIFIED_ADDR).await?;
+            return Err(e);
+        }
+    };
+
+    let mut bind_addr = listener.local_addr()?;
+    if let Some(addr) = local_addr.filter(|_| bind_addr.ip().is_unspecified()) {
+        bind_addr.set_ip(addr.ip());
+    }
+
+    // 4. send the first reply with the listening address
+    write_reply(&mut writer, 0x00, bind_addr).await?;
+    info!("Wait for incoming connection on {}", bind_addr);
+
+    // DST.ADDR is the host the client expects to connect back, domains can not be checked
+    let expected = match peer {
+        TargetAddr::Ip(addr) if !addr.ip().is_unspecified() => Some(addr.ip()),
+        _ => None,
+    };
+
+    // 5. wait for the incoming connection, give up if the client goes away
+    let accept = async {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+
+            match expected {
+                Some(ip) if ip.to_canonical() != addr.ip().to_canonical() => {
+                    warn!(
+                        "Reject incoming connection from unexpected address {}",
+                        addr
+                    );
+                }
+                _ if !config.rules.is_allowed(user.as_deref(), None, addr) => {
+                    warn!("Reject incoming connection from {} by ruleset", addr);
+                }
+                _ => return Ok::<_, Error>((stream, addr)),
+            }
+        }
+    };
+
+    // data sent by the client before the second reply is kept for the peer
+    let mut early = Vec::new();
+
+    let closed = async {
+        let mut buf = [0u8; 4096];
+
+        loop {
+            // stop reading once the buffer is full, the client waits like on a full TCP window
+            if early.len() >= BIND_EARLY_DATA_SIZE {
+                future::pending::<()>().await;
+            }
+
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => early.extend_from_slice(&buf[..n]),
+            }
+        }
+    };
+
+    let r = select! {
+        r = time::timeout(time::Duration::from_secs(BIND_TIMEOUT), accept) => r,
+        _ = closed => {
             return Err(Error::new(
-                std::io::ErrorKind::Unsupported,
-                "IPv6 address not supported",
+                ErrorKind::ConnectionAborted,
+                "Client closed before incoming connection",
             ));
         }
-        _ => {
+    };
+
+    let reader: Box<dyn AsyncRead + Unpin + Send> = match early.is_empty() {
+        true => reader,
+        false => Box::new(Cursor::new(early).chain(reader)),
+    };
+
+    let (stream, peer_addr) = match r {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            write_reply(&mut writer, reply_code(&e), bind_addr).await?;
+            return Err(e);
+        }
+        Err(_) => {
+            write_reply(&mut writer, 0x06, bind_addr).await?;
             return Err(Error::new(
-                std::io::ErrorKind::Unsupported,
-                "Unsupported address type",
-            ))
+                ErrorKind::TimedOut,
+                "No incoming connection for bind request",
+            ));
         }
     };
 
-    // 3. connect to the target server
-    let target = NetStream::Tcp(match TcpStream::connect(&addr).await {
-        Ok(stream) => stream,
+    // the listener is no longer needed, only one connection per request
+    drop(listener);
+
+    // 6. send the second reply with the connected peer address
+    write_reply(&mut writer, 0x00, peer_addr).await?;
+
+    info!("Open pipe: {} <=> {}", bind_addr, peer_addr);
+    let pipe = config
+        .pipe
+        .with_peers(client_addr.unwrap_or(capture::UNKNOWN_ADDR), peer_addr);
+    let stats = tcp::handle_forward_splitted(reader, writer, stream.into(), &pipe).await;
+    info!("Close pipe: {} <=> {} ({})", bind_addr, peer_addr, stats);
+
+    Ok(())
+}
+
+async fn write_reply<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    rep: u8,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut reply = vec![0x05, rep, 0x00];
+    TargetAddr::Ip(addr).write_to(&mut reply);
+    writer.write_all(&reply).await
+}
+
+/// The relayed datagrams are charged to the quota of the user in both directions, like the
+/// traffic of the control connection.
+#[allow(clippy::too_many_arguments)]
+async fn handle_udp_associate(
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    client: TargetAddr,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    user: Option<String>,
+    guard: Option<&QuotaGuard>,
+    config: &Config,
+) -> Result<()> {
+    // the relay is announced with the address of the control connection, which is only reachable
+    // by the client when it connected to this host directly, clients behind the reverse server
+    // get a relay there instead
+    let (local_addr, peer_addr) = match (local_addr, peer_addr) {
+        (Some(local_addr), Some(peer_addr)) => (local_addr, peer_addr),
+        (local_addr, _) => {
+            return handle_udp_tunnel(reader, writer, local_addr, user, config).await;
+        }
+    };
+
+    // 3. bind the relay socket, the client reaches it through the same address as the control connection
+    let relay = match bind_relay(local_addr).await {
+        Ok(socket) => socket,
         Err(e) => {
-            writer
-                .write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-                .await?;
-            return Err(e.into());
+            write_reply(&mut writer, reply_code(&e), UNSPECIFIED_ADDR).await?;
+            return Err(e);
         }
-    });
+    };
 
-    // 4. send success response
-    writer
-        .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-        .await?;
+    let relay_addr = SocketAddr::new(local_addr.ip(), relay.local_addr()?.port());
 
-    // 5. forward data
-    tcp::handle_forward_splitted(reader, writer, target).await
+    // 4. send success response with the relay address
+    write_reply(&mut writer, 0x00, relay_addr).await?;
+
+    info!("Open udp relay on {}", relay_addr);
+
+    let expected = expected_client(&client);
+    let mut reader = reader;
+
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut remotes = HashSet::new();
+
+    let mut control_buf = [0u8; 64];
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+
+    // 5. relay datagrams until the control connection is closed or the quota is used up
+    let result = loop {
+        select! {
+            r = reader.read(&mut control_buf) => {
+                match r {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Failed to read control connection: {}", e);
+                        break Ok(());
+                    }
+                }
+            }
+            r = relay.recv_from(&mut buf) => {
+                let (len, from) = match r {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Failed to receive datagram: {}", e);
+                        continue;
+                    }
+                };
+
+                let is_client = match client_addr {
+                    Some(addr) => addr == from,
+                    None => {
+                        !remotes.contains(&from)
+                            && is_expected_client(expected, peer_addr.ip().to_canonical(), from)
+                    }
+                };
+
+                // charged before being relayed, the relay stops once the quota is used up
+                if let Some(guard) = guard.filter(|_| is_client || remotes.contains(&from)) {
+                    if let Err(e) = guard.datagram(len).await {
+                        break Err(e);
+                    }
+                }
+
+                if is_client {
+                    client_addr = Some(from);
+
+                    let sent = send_datagram(&relay, &buf[..len], relay_addr, user.as_deref(), config);
+                    if let Some(target) = sent.await {
+                        remotes.insert(target);
+                    }
+                } else if remotes.contains(&from) {
+                    // the client address is always known once a remote is recorded
+                    let client_addr = client_addr.unwrap();
+
+                    if let Err(e) = relay.send_to(&udp_packet(from, &buf[..len]), client_addr).await {
+                        error!("Failed to forward to {}: {}", client_addr, e);
+                    }
+                } else {
+                    warn!("Drop datagram from unexpected address {}", from);
+                }
+            }
+        }
+    };
+
+    info!("Close udp relay on {}", relay_addr);
+
+    result
+}
+
+/// UDP ASSOCIATE of a client behind the reverse server, which answers the client with a relay
+/// of its own and carries the datagrams over the connection, see `handle_reverse_udp`.
+///
+/// The datagrams are charged to the quota of the user through the metered connection, which is
+/// cut once the quota is used up.
+async fn handle_udp_tunnel(
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    mut writer: Box<dyn AsyncWrite + Unpin + Send>,
+    local_addr: Option<SocketAddr>,
+    user: Option<String>,
+    config: &Config,
+) -> Result<()> {
+    let local_addr = local_addr.unwrap_or(UNSPECIFIED_ADDR);
+
+    let relay = match bind_relay(local_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            write_reply(&mut writer, reply_code(&e), UNSPECIFIED_ADDR).await?;
+            return Err(e);
+        }
+    };
+
+    let relay_addr = relay.local_addr()?;
+
+    // the address is replaced by the one of the relay on the reverse server
+    write_reply(&mut writer, 0x00, UNSPECIFIED_ADDR).await?;
+    writer.flush().await?;
+
+    info!("Open udp tunnel on {}", relay_addr);
+
+    let remotes = Mutex::new(HashSet::new());
+
+    // datagrams of the client, until the reverse server closes the connection
+    let up = async {
+        loop {
+            let packet = match read_datagram(&mut reader).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read udp tunnel: {}", e);
+                    break;
+                }
+            };
+
+            let sent = send_datagram(&relay, &packet, relay_addr, user.as_deref(), config);
+            if let Some(target) = sent.await {
+                remotes.lock().unwrap().insert(target);
+            }
+        }
+    };
+
+    // answers of the remotes
+    let down = async {
+        let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+
+        loop {
+            let (len, from) = match relay.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+
+            if !remotes.lock().unwrap().contains(&from) {
+                warn!("Drop datagram from unexpected address {}", from);
+                continue;
+            }
+
+            if let Err(e) = write_datagram(&mut writer, &udp_packet(from, &buf[..len])).await {
+                error!("Failed to write udp tunnel: {}", e);
+                break;
+            }
+        }
+    };
+
+    select! {
+        _ = up => {}
+        _ = down => {}
+    }
+
+    info!("Close udp tunnel on {}", relay_addr);
+
+    Ok(())
+}
+
+/// Serve a client of the reverse server up to its SOCKS5 request, returns the streams back to be
+/// piped unless the client asked for UDP ASSOCIATE.
+///
+/// The clients can not reach the agents behind the reverse server, so the reverse server follows
+/// the handshake and answers UDP ASSOCIATE with a relay of its own, whose datagrams are carried to
+/// the agent over the connection, each prefixed with its length in 2 bytes. The user is only known
+/// to the agent, which charges the datagrams to its quota.
+pub async fn handle_reverse_udp(
+    mut client: NetStream,
+    client_addr: SocketAddr,
+    mut agent: NetStream,
+    config: &Config,
+) -> Result<Option<(NetStream, NetStream)>> {
+    let request = config
+        .handshake(relay_handshake(&mut client, &mut agent))
+        .await?;
+
+    let expected = match request {
+        Some(addr) => expected_client(&addr),
+        None => return Ok(Some((client, agent))),
+    };
+
+    let relay = match client.local_addr() {
+        Ok(local_addr) => bind_relay(local_addr)
+            .await
+            .map(|relay| (local_addr, relay)),
+        Err(e) => Err(e),
+    };
+
+    let (relay, relay_addr) = match relay {
+        Ok((local_addr, relay)) => {
+            let relay_addr = SocketAddr::new(local_addr.ip(), relay.local_addr()?.port());
+            (relay, relay_addr)
+        }
+        Err(e) => {
+            write_reply(&mut client, reply_code(&e), UNSPECIFIED_ADDR).await?;
+            client.flush().await?;
+            return Err(e);
+        }
+    };
+
+    write_reply(&mut client, 0x00, relay_addr).await?;
+    client.flush().await?;
+
+    info!("Open udp relay on {} for {}", relay_addr, client_addr);
+
+    let (mut control, _client_writer) = client.split();
+    let (mut agent_reader, mut agent_writer) = agent.split();
+
+    let udp_client: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+    // the association ends with the control connection
+    let closed = async {
+        let mut buf = [0u8; 64];
+        while let Ok(1..) = control.read(&mut buf).await {}
+    };
+
+    let up = async {
+        let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+
+        loop {
+            let (len, from) = match relay.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+
+            let is_client = {
+                let mut udp_client = udp_client.lock().unwrap();
+
+                match *udp_client {
+                    Some(addr) => addr == from,
+                    None if is_expected_client(expected, client_addr.ip().to_canonical(), from) => {
+                        *udp_client = Some(from);
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            if !is_client {
+                warn!("Drop datagram from unexpected address {}", from);
+                continue;
+            }
+
+            if let Err(e) = write_datagram(&mut agent_writer, &buf[..len]).await {
+                error!("Failed to write udp tunnel: {}", e);
+                break;
+            }
+        }
+    };
+
+    let down = async {
+        loop {
+            let packet = match read_datagram(&mut agent_reader).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read udp tunnel: {}", e);
+                    break;
+                }
+            };
+
+            // the agent only answers once the client has sent a datagram
+            let Some(addr) = *udp_client.lock().unwrap() else {
+                continue;
+            };
+
+            if let Err(e) = relay.send_to(&packet, addr).await {
+                error!("Failed to forward to {}: {}", addr, e);
+            }
+        }
+    };
+
+    select! {
+        _ = closed => {}
+        _ = up => {}
+        _ = down => {}
+    }
+
+    info!("Close udp relay on {} for {}", relay_addr, client_addr);
+
+    Ok(None)
+}
+
+/// Relay the SOCKS5 handshake of a client to an agent, returns the address announced by a UDP
+/// ASSOCIATE request which the agent accepted, `None` once anything else has been passed on.
+async fn relay_handshake(
+    client: &mut NetStream,
+    agent: &mut NetStream,
+) -> Result<Option<TargetAddr>> {
+    let version = client.read_u8().await?;
+    write_flush(agent, &[version]).await?;
+
+    if version != 0x05 {
+        return Ok(None);
+    }
+
+    // 1. auth negotiation
+    let nmethods = client.read_u8().await?;
+    let mut greeting = vec![nmethods; 1 + nmethods as usize];
+    client.read_exact(&mut greeting[1..]).await?;
+    write_flush(agent, &greeting).await?;
+
+    let mut method = [0u8; 2];
+    agent.read_exact(&mut method).await?;
+    write_flush(client, &method).await?;
+
+    match method[1] {
+        0x00 => {}
+        0x02 => {
+            // username and password, see RFC 1929
+            let mut auth = vec![0u8; 2];
+            client.read_exact(&mut auth).await?;
+            let ulen = auth[1];
+            read_field(client, &mut auth, ulen).await?;
+            let plen = client.read_u8().await?;
+            auth.push(plen);
+            read_field(client, &mut auth, plen).await?;
+            write_flush(agent, &auth).await?;
+
+            let mut status = [0u8; 2];
+            agent.read_exact(&mut status).await?;
+            write_flush(client, &status).await?;
+
+            if status[1] != 0x00 {
+                return Ok(None);
+            }
+        }
+        _ => return Ok(None),
+    }
+
+    // 2. request
+    let mut request = vec![0u8; 4];
+    client.read_exact(&mut request).await?;
+    let addr = read_addr(client, request[3]).await?;
+    // the address type is written again with the address
+    request.truncate(3);
+    addr.write_to(&mut request);
+    write_flush(agent, &request).await?;
+
+    if request[1] != 0x03 {
+        return Ok(None);
+    }
+
+    let mut reply = vec![0u8; 4];
+    agent.read_exact(&mut reply).await?;
+    let bound = read_addr(agent, reply[3]).await?;
+
+    // the relay of the reverse server replaces the one of the agent
+    if reply[1] != 0x00 {
+        reply.truncate(3);
+        bound.write_to(&mut reply);
+        write_flush(client, &reply).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(addr))
+}
+
+async fn read_field(reader: &mut NetStream, buf: &mut Vec<u8>, len: u8) -> Result<()> {
+    let start = buf.len();
+    buf.resize(start + len as usize, 0);
+    reader.read_exact(&mut buf[start..]).await?;
+    Ok(())
+}
+
+async fn write_flush<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W, buf: &[u8]) -> Result<()> {
+    writer.write_all(buf).await?;
+    writer.flush().await
+}
+
+/// Read a datagram carried over a stream, `None` once the stream is closed.
+async fn read_datagram<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut packet = vec![0u8; len];
+    reader.read_exact(&mut packet).await?;
+
+    Ok(Some(packet))
+}
+
+/// Write a datagram over a stream, datagrams which do not fit the length prefix are dropped.
+async fn write_datagram<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    packet: &[u8],
+) -> Result<()> {
+    let len = match u16::try_from(packet.len()) {
+        Ok(len) => len,
+        Err(_) => {
+            warn!("Drop datagram of {} bytes", packet.len());
+            return Ok(());
+        }
+    };
+
+    let mut frame = Vec::with_capacity(2 + packet.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(packet);
+
+    write_flush(writer, &frame).await
+}
+
+/// Bind a relay socket of the family of `local_addr`.
+async fn bind_relay(local_addr: SocketAddr) -> Result<UdpSocket> {
+    let bind_ip = match local_addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await
+}
+
+/// Send a datagram of the client to the destination in its header, returns the destination
+/// whose answers go back to the client.
+async fn send_datagram(
+    relay: &UdpSocket,
+    packet: &[u8],
+    relay_addr: SocketAddr,
+    user: Option<&str>,
+    config: &Config,
+) -> Option<SocketAddr> {
+    let (target, payload) = match parse_udp_header(packet) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Drop datagram to invalid destination: {}", e);
+            return None;
+        }
+    };
+
+    let resolved = match target.resolve(config.dialer.resolver(), relay_addr).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Failed to resolve {}: {}", target, e);
+            return None;
+        }
+    };
+
+    let domain = match &target {
+        TargetAddr::Domain(domain, _) => Some(domain.as_str()),
+        TargetAddr::Ip(_) => None,
+    };
+
+    if !config.rules.is_allowed(user, domain, resolved) {
+        warn!("Drop datagram to {} by ruleset", target);
+        return None;
+    }
+
+    let target = to_relay_family(resolved, relay_addr);
+
+    if let Err(e) = relay.send_to(payload, target).await {
+        error!("Failed to forward to {}: {}", target, e);
+    }
+
+    Some(target)
+}
+
+/// Encapsulate a datagram of a remote for the client, see RFC 1928 section 7.
+fn udp_packet(from: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x00, 0x00, 0x00];
+    let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+    TargetAddr::Ip(from).write_to(&mut packet);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// The client may announce the address it will send from, zero fields mean unknown.
+fn expected_client(client: &TargetAddr) -> Option<SocketAddr> {
+    match client {
+        TargetAddr::Ip(addr) => Some(*addr),
+        TargetAddr::Domain(..) => None,
+    }
+}
+
+/// Map IPv4 targets into the IPv4-mapped IPv6 space when the relay socket is IPv6.
+fn to_relay_family(addr: SocketAddr, relay_addr: SocketAddr) -> SocketAddr {
+    match (addr, relay_addr) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Whether the first datagram of an association comes from its client.
+///
+/// The client may announce the address it sends from, otherwise it has to send from the IP of
+/// the control connection, so that a stranger can not take the association over.
+fn is_expected_client(expected: Option<SocketAddr>, peer_ip: IpAddr, from: SocketAddr) -> bool {
+    let from_ip = from.ip().to_canonical();
+
+    match expected {
+        Some(addr) if !addr.ip().is_unspecified() => {
+            addr.ip().to_canonical() == from_ip && (addr.port() == 0 || addr.port() == from.port())
+        }
+        Some(addr) => from_ip == peer_ip && (addr.port() == 0 || addr.port() == from.port()),
+        None => from_ip == peer_ip,
+    }
+}
+
+async fn read_addr<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, atyp: u8) -> Result<TargetAddr> {
+    match atyp {
+        0x01 => {
+            // IPv4
+            let mut addr = [0u8; 4];
+            reader.read_exact(&mut addr).await?;
+            let port = reader.read_u16().await?;
+            Ok(TargetAddr::Ip(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::from(addr)),
+                port,
+            )))
+        }
+        0x03 => {
+            // domain
+            let len = reader.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            reader.read_exact(&mut domain).await?;
+            let port = reader.read_u16().await?;
+            Ok(TargetAddr::Domain(
+                String::from_utf8_lossy(&domain).to_string(),
+                port,
+            ))
+        }
+        0x04 => {
+            // IPv6
+            let mut addr = [0u8; 16];
+            reader.read_exact(&mut addr).await?;
+            let port = reader.read_u16().await?;
+            Ok(TargetAddr::Ip(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(addr)),
+                port,
+            )))
+        }
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unsupported address type",
+        )),
+    }
+}
+
+/// Split a client datagram into its destination and payload, see RFC 1928 section 7.
+fn parse_udp_header(packet: &[u8]) -> Result<(TargetAddr, &[u8])> {
+    if packet.len() < 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "Datagram too short"));
+    }
+
+    // standalone datagrams only, fragment reassembly is optional in the RFC
+    if packet[2] != 0x00 {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Fragmented datagram not supported",
+        ));
+    }
+
+    let (addr, offset) = match packet[3] {
+        0x01 => {
+            if packet.len() < 10 {
+                return Err(Error::new(ErrorKind::InvalidData, "Datagram too short"));
+            }
+            let ip = Ipv4Addr::new(packet[4], packet[5], packet[6], packet[7]);
+            let port = u16::from_be_bytes([packet[8], packet[9]]);
+            (TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port)), 10)
+        }
+        0x03 => {
+            let len = *packet
+                .get(4)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Datagram too short"))?
+                as usize;
+            if packet.len() < 7 + len {
+                return Err(Error::new(ErrorKind::InvalidData, "Datagram too short"));
+            }
+            let domain = String::from_utf8_lossy(&packet[5..5 + len]).to_string();
+            let port = u16::from_be_bytes([packet[5 + len], packet[6 + len]]);
+            (TargetAddr::Domain(domain, port), 7 + len)
+        }
+        0x04 => {
+            if packet.len() < 22 {
+                return Err(Error::new(ErrorKind::InvalidData, "Datagram too short"));
+            }
+            let ip: [u8; 16] = packet[4..20].try_into().unwrap();
+            let port = u16::from_be_bytes([packet[20], packet[21]]);
+            (
+                TargetAddr::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)),
+                22,
+            )
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unsupported address type",
+            ))
+        }
+    };
+
+    Ok((addr, &packet[offset..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io;
+
+    /// Start an association of a client at `peer_addr`, returns the control stream and the relay.
+    ///
+    /// The client logs in as `alice` when the config requires authentication.
+    async fn associate(
+        config: Config,
+        peer_addr: SocketAddr,
+        client: TargetAddr,
+    ) -> (io::DuplexStream, SocketAddr) {
+        let (mut control, server) = io::duplex(1024);
+        let (reader, writer) = io::split(server);
+        let local_addr = "127.0.0.1:1080".parse().ok();
+        let auth = config.auth_info.is_some();
+
+        tokio::spawn(async move {
+            handle_connection_splitted(
+                Box::new(reader),
+                Box::new(writer),
+                local_addr,
+                Some(peer_addr),
+                &config,
+            )
+            .await
+        });
+
+        let method = if auth { 0x02 } else { 0x00 };
+        control.write_all(&[0x05, 0x01, method]).await.unwrap();
+
+        let mut reply = [0u8; 2];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, method]);
+
+        if auth {
+            control.write_all(b"\x01\x05alice\x04pass").await.unwrap();
+            control.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x01, 0x00]);
+        }
+
+        let mut request = vec![0x05, 0x03, 0x00];
+        client.write_to(&mut request);
+        control.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        let relay = match read_addr(&mut control, reply[3]).await.unwrap() {
+            TargetAddr::Ip(addr) => addr,
+            addr => panic!("relay announced as {}", addr),
+        };
+
+        (control, relay)
+    }
+
+    fn targets() -> Vec<TargetAddr> {
+        vec![
+            TargetAddr::Ip("10.0.0.1:53".parse().unwrap()),
+            TargetAddr::Ip("[2001:db8::1]:443".parse().unwrap()),
+            TargetAddr::Domain("example.com".to_string(), 8080),
+        ]
+    }
+
+    #[test]
+    fn udp_header_round_trip() {
+        for target in targets() {
+            let mut packet = vec![0x00, 0x00, 0x00];
+            target.write_to(&mut packet);
+            packet.extend_from_slice(b"payload");
+
+            let (addr, payload) = parse_udp_header(&packet).unwrap();
+            assert_eq!(addr, target);
+            assert_eq!(payload, b"payload");
+
+            // every truncation of the header is refused
+            let header = packet.len() - b"payload".len();
+            for len in 0..header {
+                assert!(
+                    parse_udp_header(&packet[..len]).is_err(),
+                    "{} {}",
+                    target,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn udp_header_fragment() {
+        let mut packet = vec![0x00, 0x00, 0x01];
+        TargetAddr::Ip("10.0.0.1:53".parse().unwrap()).write_to(&mut packet);
+
+        let e = parse_udp_header(&packet).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn read_addr_round_trip() {
+        for target in targets() {
+            let mut buf = Vec::new();
+            target.write_to(&mut buf);
+
+            let mut reader = &buf[1..];
+            assert_eq!(read_addr(&mut reader, buf[0]).await.unwrap(), target);
+            assert!(reader.is_empty());
+        }
+
+        let mut reader: &[u8] = &[0, 0, 0, 0];
+        assert!(read_addr(&mut reader, 0x02).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_associate_ignores_strangers() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        // the client announces no address, its datagrams must come from the control connection
+        let client = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let (_control, relay) = associate(
+            Config::default(),
+            "127.0.0.2:40000".parse().unwrap(),
+            TargetAddr::Ip(UNSPECIFIED_ADDR),
+        )
+        .await;
+
+        let mut packet = vec![0x00, 0x00, 0x00];
+        TargetAddr::Ip(target_addr).write_to(&mut packet);
+
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut from_stranger = packet.clone();
+        from_stranger.extend_from_slice(b"stranger");
+        stranger.send_to(&from_stranger, relay).await.unwrap();
+
+        let mut from_client = packet;
+        from_client.extend_from_slice(b"client");
+        client.send_to(&from_client, relay).await.unwrap();
+
+        // only the datagram of the client reaches the target
+        let mut buf = [0u8; 64];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"client");
+        assert!(
+            time::timeout(Duration::from_millis(100), target.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        // and the answer goes back to the client, not to the stranger
+        target.send_to(b"answer", from).await.unwrap();
+
+        let len = client.recv(&mut buf).await.unwrap();
+        let (addr, payload) = parse_udp_header(&buf[..len]).unwrap();
+        assert_eq!(addr, TargetAddr::Ip(target_addr));
+        assert_eq!(payload, b"answer");
+    }
+
+    #[tokio::test]
+    async fn udp_associate_charges_quota() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let config = Config {
+            auth_info: Some(AuthInfo::new("alice:pass".to_string())),
+            quotas: Quotas::new(&["alice bytes=100/day".to_string()], None, None).unwrap(),
+            ..Default::default()
+        };
+        let quotas = config.quotas.clone();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_control, relay) = associate(
+            config,
+            "127.0.0.1:40000".parse().unwrap(),
+            TargetAddr::Ip(UNSPECIFIED_ADDR),
+        )
+        .await;
+
+        // the control connection alone stays below the quota
+        assert!(quotas.acquire(Some("alice")).is_ok());
+
+        let mut packet = vec![0x00, 0x00, 0x00];
+        TargetAddr::Ip(target_addr).write_to(&mut packet);
+        packet.extend_from_slice(&[0u8; 50]);
+        client.send_to(&packet, relay).await.unwrap();
+
+        let mut buf = [0u8; 128];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 50);
+
+        // the answer is charged as well and uses up the quota
+        target.send_to(&[0u8; 50], from).await.unwrap();
+        client.recv(&mut buf).await.unwrap();
+
+        let e = quotas.acquire(Some("alice")).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+
+        // and no datagram is relayed any more
+        client.send_to(&packet, relay).await.unwrap();
+        assert!(
+            time::timeout(Duration::from_millis(100), target.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn expected_client() {
+        let peer_ip = "10.0.0.1".parse().unwrap();
+        let from = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        // unknown address, only the IP of the control connection is accepted
+        assert!(is_expected_client(None, peer_ip, from("10.0.0.1:5000")));
+        assert!(!is_expected_client(None, peer_ip, from("10.0.0.2:5000")));
+
+        let unspecified = Some(from("0.0.0.0:0"));
+        assert!(is_expected_client(
+            unspecified,
+            peer_ip,
+            from("10.0.0.1:5000")
+        ));
+        assert!(!is_expected_client(
+            unspecified,
+            peer_ip,
+            from("10.0.0.2:5000")
+        ));
+
+        let port = Some(from("0.0.0.0:5000"));
+        assert!(is_expected_client(port, peer_ip, from("10.0.0.1:5000")));
+        assert!(!is_expected_client(port, peer_ip, from("10.0.0.1:5001")));
+
+        // an announced address is trusted, e.g. a client behind a NAT
+        let announced = Some(from("192.168.1.2:0"));
+        assert!(is_expected_client(
+            announced,
+            peer_ip,
+            from("192.168.1.2:5000")
+        ));
+        assert!(!is_expected_client(
+            announced,
+            peer_ip,
+            from("10.0.0.1:5000")
+        ));
+    }
+
+    #[tokio::test]
+    async fn reverse_udp_associate() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        // the client connects to the reverse server, which holds a connection of the agent
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut control = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, client_addr) = listener.accept().await.unwrap();
+        let agent = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (server_agent, _) = listener.accept().await.unwrap();
+
+        tokio::spawn(async move {
+            let config = Config::default();
+            handle_connection(agent.into(), None, &config).await
+        });
+        tokio::spawn(async move {
+            let config = Config::default();
+            handle_reverse_udp(client.into(), client_addr, server_agent.into(), &config).await
+        });
+
+        control
+            .write_all(&[0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+        // the relay is on the reverse server
+        let relay = SocketAddr::new(addr.ip(), u16::from_be_bytes([reply[10], reply[11]]));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packet = vec![0x00, 0x00, 0x00];
+        TargetAddr::Ip(target_addr).write_to(&mut packet);
+        packet.extend_from_slice(b"hello");
+        client.send_to(&packet, relay).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        target.send_to(b"world", from).await.unwrap();
+
+        let len = client.recv(&mut buf).await.unwrap();
+        let (addr, payload) = parse_udp_header(&buf[..len]).unwrap();
+        assert_eq!(addr, TargetAddr::Ip(target_addr));
+        assert_eq!(payload, b"world");
+    }
+
+    #[tokio::test]
+    async fn bind_keeps_early_data() {
+        let (mut control, server) = io::duplex(1024);
+        let (reader, writer) = io::split(server);
+        let local_addr = "127.0.0.1:1080".parse().ok();
+
+        tokio::spawn(async move {
+            let config = Config::default();
+            handle_connection_splitted(
+                Box::new(reader),
+                Box::new(writer),
+                local_addr,
+                None,
+                &config,
+            )
+            .await
+        });
+
+        control
+            .write_all(&[0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[2..4], [0x05, 0x00]);
+
+        let bind_addr = SocketAddr::new(
+            "127.0.0.1".parse().unwrap(),
+            u16::from_be_bytes([reply[10], reply[11]]),
+        );
+
+        // sent before the peer connected, it must not abort the request
+        control.write_all(b"early").await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut peer = tokio::net::TcpStream::connect(bind_addr).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        control.write_all(b" late").await.unwrap();
+
+        let mut buf = [0u8; 10];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"early late");
+    }
+
+    #[tokio::test]
+    async fn connect_replies_and_forwards() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+
+        let (mut control, server) = io::duplex(1024);
+        let (reader, writer) = io::split(server);
+
+        tokio::spawn(async move {
+            let config = Config::default();
+            handle_connection_splitted(Box::new(reader), Box::new(writer), None, None, &config)
+                .await
+        });
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00];
+        TargetAddr::Ip(target_addr).write_to(&mut request);
+        request.extend_from_slice(b"ping");
+        control.write_all(&request).await.unwrap();
+
+        let (mut target, from) = listener.accept().await.unwrap();
+
+        let mut reply = [0u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+        assert_eq!(u16::from_be_bytes([reply[10], reply[11]]), from.port());
+
+        let mut buf = [0u8; 4];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        target.write_all(b"pong").await.unwrap();
+        control.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
 }
@@ -0,0 +1,220 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::Shutdown,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use socket2::SockRef;
+use tokio::io::Interest;
+use tracing::{error, warn};
+
+use crate::{
+    limit::Limiter,
+    tcp::{self, Meter, NetStream, PipeConfig, PipeStats},
+    transport::Socket,
+};
+
+/// Bytes moved by a single splice call, the default capacity of a pipe.
+const SPLICE_SIZE: usize = 65536;
+
+/// Kernel pipe the data of one direction goes through.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+/// Relay two plain sockets with `splice(2)`, the data never leaves the kernel.
+///
+/// Behaves like `tcp::handle_forward`, the streams are given back when the fast path can not be used.
+pub async fn handle_forward(
+    stream1: NetStream,
+    stream2: NetStream,
+    config: &PipeConfig,
+) -> std::result::Result<PipeStats, (NetStream, NetStream)> {
+    // TLS and other carriers which transform the bytes have to be copied through userspace
+    let (socket1, socket2) = match (stream1.into_socket(), stream2.into_socket()) {
+        (Ok(socket1), Ok(socket2)) => (socket1, socket2),
+        (socket1, socket2) => {
+            return Err((
+                socket1.map_or_else(|stream| stream, Socket::into_stream),
+                socket2.map_or_else(|stream| stream, Socket::into_stream),
+            ))
+        }
+    };
+
+    let (pipe1, pipe2) = match (Pipe::new(), Pipe::new()) {
+        (Ok(pipe1), Ok(pipe2)) => (pipe1, pipe2),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Failed to create pipes, fall back to userspace copy: {}", e);
+            return Err((socket1.into_stream(), socket2.into_stream()));
+        }
+    };
+
+    let meter = Meter::new();
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+
+    // the first stream is the client side, its traffic goes up
+    let throttle = config.limits.connection();
+
+    let (reason, finished) = tcp::drive(
+        copy(&socket1, &socket2, &pipe1, &throttle.up, &sent, &meter),
+        copy(
+            &socket2,
+            &socket1,
+            &pipe2,
+            &throttle.down,
+            &received,
+            &meter,
+        ),
+        &meter,
+        config,
+    )
+    .await;
+
+    // the copies have been dropped by a timeout, close both sides instead of leaving them hanging
+    if !finished {
+        let _ = socket1.shutdown_write();
+        let _ = socket2.shutdown_write();
+    }
+
+    Ok(PipeStats {
+        sent: sent.into_inner(),
+        received: received.into_inner(),
+        duration: meter.elapsed(),
+        reason,
+    })
+}
+
+/// Splice until EOF at the rate allowed by `limiter`, then shut down the writer.
+async fn copy(
+    from: &Socket,
+    to: &Socket,
+    pipe: &Pipe,
+    limiter: &Limiter,
+    bytes: &AtomicU64,
+    meter: &Meter,
+) -> Result<()> {
+    let result = async {
+        loop {
+            // the pipe is empty here, so it can only block on the socket
+            let n = loop {
+                from.ready(Interest::READABLE).await?;
+
+                match from.try_io(Interest::READABLE, || {
+                    splice(from.as_raw_fd(), pipe.write.as_raw_fd(), SPLICE_SIZE)
+                }) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    result => break result?,
+                }
+            };
+
+            if n == 0 {
+                return to.shutdown_write();
+            }
+
+            let mut left = n;
+
+            while left > 0 {
+                to.ready(Interest::WRITABLE).await?;
+
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                }) {
+                    Ok(written) => left -= written,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            bytes.fetch_add(n as u64, Ordering::Relaxed);
+            meter.touch();
+
+            limiter.wait(n).await;
+
+            // waiting for the limit does not make the pipe idle
+            meter.touch();
+        }
+    }
+    .await;
+
+    if let Err(e) = &result {
+        error!("Failed to splice: {}", e);
+
+        // still tell the peer, the other direction may be waiting for it
+        let _ = to.shutdown_write();
+    }
+
+    result
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> Result<usize> {
+    // SAFETY: both descriptors are owned by the caller for the duration of the call
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    match n {
+        n if n < 0 => Err(Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+impl Socket {
+    async fn ready(&self, interest: Interest) -> Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.ready(interest).await.map(|_| ()),
+            Self::Unix(stream) => stream.ready(interest).await.map(|_| ()),
+        }
+    }
+
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        match self {
+            Self::Tcp(stream) => stream.try_io(interest, f),
+            Self::Unix(stream) => stream.try_io(interest, f),
+        }
+    }
+
+    fn shutdown_write(&self) -> Result<()> {
+        SockRef::from(self).shutdown(Shutdown::Write)
+    }
+}
+
+impl AsFd for Socket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(stream) => stream.as_fd(),
+            Self::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+impl Pipe {
+    fn new() -> Result<Self> {
+        let mut fds = [0; 2];
+
+        // SAFETY: pipe2 fills the array with two new descriptors on success
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // SAFETY: the descriptors have just been created and are not owned by anything else
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        Ok(Self { read, write })
+    }
+}
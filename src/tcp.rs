@@ -1,100 +1,408 @@
-use std::io::Result;
-use std::sync::Arc;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-use rustls::pki_types::ServerName;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::{io, net::TcpStream, select};
-use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::{join, select, time};
 use tracing::error;
 
-#[cfg(target_family = "unix")]
-use tokio::net::UnixStream;
+use crate::capture::{self, Capture, Direction, TcpFlow};
+use crate::limit::{Limiter, RateLimits};
+use crate::transport::Transport;
 
-pub enum NetStream {
-    Tcp(TcpStream),
-    #[cfg(target_family = "unix")]
-    Unix(UnixStream),
-    ServerTls(server::TlsStream<TcpStream>),
-    ClientTls(client::TlsStream<TcpStream>),
+#[cfg(target_os = "linux")]
+use crate::splice;
+
+/// How long the write sides of a timed out pipe may take to shut down.
+const SHUTDOWN_TIMEOUT: u64 = 5;
+
+const COPY_BUFFER_SIZE: usize = 8192;
+
+/// Stream of any carrier, see `transport::Transport`.
+pub type NetStream = Box<dyn Transport>;
+
+/// Timeouts, bandwidth limits and capture of the pipes, `None` disables the timeouts.
+#[derive(Clone, Default)]
+pub struct PipeConfig {
+    /// Close the pipe when no data has been transferred in either direction for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Close the pipe when it has been open for this long.
+    pub max_lifetime: Option<Duration>,
+    pub limits: RateLimits,
+    pub capture: Option<Capture>,
+    /// Client and target addresses recorded in the capture of a single pipe.
+    pub peers: Option<(SocketAddr, SocketAddr)>,
 }
 
-impl NetStream {
-    pub async fn from_acceptor(stream: TcpStream, acceptor: Arc<Option<TlsAcceptor>>) -> Self {
-        match acceptor.as_ref() {
-            Some(acceptor) => Self::ServerTls(acceptor.accept(stream).await.unwrap()),
-            None => Self::Tcp(stream),
+impl PipeConfig {
+    /// Config of a new listener bound to `addrs`, its connections share the listener bandwidth
+    /// limits. Pipes which do not come from a listener pass no address.
+    pub fn listener(&self, addrs: &[SocketAddr]) -> Self {
+        Self {
+            limits: self.limits.listener(),
+            capture: self
+                .capture
+                .clone()
+                .filter(|capture| capture.captures(addrs)),
+            ..self.clone()
         }
     }
 
-    pub async fn from_connector(stream: TcpStream, connector: Arc<Option<TlsConnector>>) -> Self {
-        match connector.as_ref() {
-            Some(connector) => Self::ClientTls(
-                connector
-                    .connect(ServerName::try_from("localhost").unwrap(), stream)
-                    .await
-                    .unwrap(),
-            ),
-            None => Self::Tcp(stream),
+    /// Config of a single pipe between `client` and `target`.
+    pub fn with_peers(&self, client: SocketAddr, target: SocketAddr) -> Self {
+        Self {
+            peers: Some((client, target)),
+            ..self.clone()
         }
     }
+}
 
-    pub fn split(
-        self,
-    ) -> (
-        Box<dyn AsyncRead + Unpin + Send>,
-        Box<dyn AsyncWrite + Unpin + Send>,
-    ) {
+/// Traffic of a pipe, `sent` goes from the first stream to the second and `received` back.
+#[must_use]
+pub struct PipeStats {
+    pub sent: u64,
+    pub received: u64,
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
+/// Why a pipe was closed.
+pub enum CloseReason {
+    /// Both sides have sent EOF.
+    Eof,
+    /// No data has been transferred for the idle timeout.
+    Idle,
+    /// The pipe has reached its maximum lifetime.
+    Lifetime,
+    /// Reading or writing one of the sides failed.
+    Error(Error),
+}
+
+impl CloseReason {
+    /// Keep the first reason other than EOF, later failures are usually consequences of it.
+    fn or(self, other: Self) -> Self {
         match self {
-            NetStream::Tcp(stream) => {
-                let (r, w) = io::split(stream);
-                (Box::new(r), Box::new(w))
-            }
-            #[cfg(target_family = "unix")]
-            NetStream::Unix(stream) => {
-                let (r, w) = io::split(stream);
-                (Box::new(r), Box::new(w))
-            }
-            NetStream::ServerTls(stream) => {
-                let (r, w) = io::split(stream);
-                (Box::new(r), Box::new(w))
-            }
-            NetStream::ClientTls(stream) => {
-                let (r, w) = io::split(stream);
-                (Box::new(r), Box::new(w))
-            }
+            CloseReason::Eof => other,
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for PipeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "=> {} bytes, <= {} bytes, {:.2?}, {}",
+            self.sent, self.received, self.duration, self.reason
+        )
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Eof => write!(f, "closed by eof"),
+            CloseReason::Idle => write!(f, "closed by idle timeout"),
+            CloseReason::Lifetime => write!(f, "closed by max lifetime"),
+            CloseReason::Error(e) => write!(f, "closed by error: {}", e),
         }
     }
 }
 
-pub async fn handle_forward(stream1: NetStream, stream2: NetStream) -> Result<()> {
+pub async fn handle_forward(
+    stream1: NetStream,
+    stream2: NetStream,
+    config: &PipeConfig,
+) -> PipeStats {
+    // plain sockets are spliced in the kernel, TLS streams are copied through userspace,
+    // and so are captured pipes whose payload has to be seen
+    #[cfg(target_os = "linux")]
+    let (stream1, stream2) = match config.capture {
+        Some(_) => (stream1, stream2),
+        None => match splice::handle_forward(stream1, stream2, config).await {
+            Ok(stats) => return stats,
+            Err(streams) => streams,
+        },
+    };
+
     let (r1, w1) = stream1.split();
 
-    handle_forward_splitted(r1, w1, stream2).await
+    handle_forward_splitted(r1, w1, stream2, config).await
 }
 
+/// Relay both directions until each one has finished, or until a timeout of `config` fires.
+///
+/// The EOF of one side is passed on as a write shutdown of the other side, so the opposite
+/// direction keeps flowing for protocols which half-close the connection, until its own EOF or
+/// the idle timeout.
 pub async fn handle_forward_splitted(
     mut r1: Box<dyn AsyncRead + Send + Unpin>,
     mut w1: Box<dyn AsyncWrite + Send + Unpin>,
     stream2: NetStream,
-) -> Result<()> {
+    config: &PipeConfig,
+) -> PipeStats {
     let (mut r2, mut w2) = stream2.split();
 
-    let handle1 = async {
-        if let Err(e) = tokio::io::copy(&mut r1, &mut w2).await {
-            error!("Failed to copy: {}", e);
+    let meter = Meter::new();
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+
+    // the first stream is the client side, its traffic goes up
+    let throttle = config.limits.connection();
+
+    let flow = config.capture.as_ref().map(|capture| {
+        let (client, target) = config
+            .peers
+            .unwrap_or((capture::UNKNOWN_ADDR, capture::UNKNOWN_ADDR));
+        capture.tcp(client, target)
+    });
+
+    let (reason, finished) = drive(
+        copy(
+            &mut r1,
+            &mut w2,
+            &throttle.up,
+            &sent,
+            &meter,
+            flow.as_ref().map(|flow| (flow, Direction::Up)),
+        ),
+        copy(
+            &mut r2,
+            &mut w1,
+            &throttle.down,
+            &received,
+            &meter,
+            flow.as_ref().map(|flow| (flow, Direction::Down)),
+        ),
+        &meter,
+        config,
+    )
+    .await;
+
+    if let Some(flow) = &flow {
+        if !matches!(reason, CloseReason::Eof) {
+            flow.reset();
         }
-    };
+    }
+
+    // the copies have been dropped by a timeout, close both sides instead of leaving them hanging
+    if !finished {
+        let shutdown = async {
+            let _ = join!(w1.shutdown(), w2.shutdown());
+        };
+        let _ = time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT), shutdown).await;
+    }
 
-    let handle2 = async {
-        if let Err(e) = tokio::io::copy(&mut r2, &mut w1).await {
-            error!("Failed to copy: {}", e);
+    PipeStats {
+        sent: sent.into_inner(),
+        received: received.into_inner(),
+        duration: meter.elapsed(),
+        reason,
+    }
+}
+
+/// Activity of a pipe, shared by its two directions.
+pub(crate) struct Meter {
+    start: Instant,
+    /// Milliseconds from `start` to the last transfer.
+    activity: AtomicU64,
+}
+
+impl Meter {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            activity: AtomicU64::new(0),
         }
-    };
+    }
 
-    select! {
-        _ = handle1 => {},
-        _ = handle2 => {},
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
     }
 
-    Ok(())
+    /// Record a transfer, which resets the idle timeout.
+    pub(crate) fn touch(&self) {
+        self.activity
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Run both directions of a pipe until each one has finished or a timeout of `config` fires.
+///
+/// Returns why the pipe was closed, and whether both directions have finished.
+pub(crate) async fn drive(
+    pipe1: impl Future<Output = Result<()>>,
+    pipe2: impl Future<Output = Result<()>>,
+    meter: &Meter,
+    config: &PipeConfig,
+) -> (CloseReason, bool) {
+    let mut pipe1 = pin!(pipe1);
+    let mut pipe2 = pin!(pipe2);
+
+    let deadline = config.max_lifetime.map(|lifetime| meter.start + lifetime);
+
+    let (mut done1, mut done2) = (false, false);
+    let mut reason = CloseReason::Eof;
+
+    let idle = config.idle_timeout;
+
+    while !(done1 && done2) {
+        let last = meter.activity.load(Ordering::Relaxed);
+        let idle_at = meter.start + Duration::from_millis(last) + idle.unwrap_or_default();
+
+        select! {
+            result = &mut pipe1, if !done1 => {
+                done1 = true;
+                if let Err(e) = result {
+                    reason = reason.or(CloseReason::Error(e));
+                }
+            }
+            result = &mut pipe2, if !done2 => {
+                done2 = true;
+                if let Err(e) = result {
+                    reason = reason.or(CloseReason::Error(e));
+                }
+            }
+            _ = time::sleep_until(idle_at.into()), if idle.is_some() => {
+                // data may have been transferred right before the timer fired
+                if meter.activity.load(Ordering::Relaxed) == last {
+                    reason = reason.or(CloseReason::Idle);
+                    break;
+                }
+            }
+            _ = time::sleep_until(deadline.unwrap_or(meter.start).into()), if deadline.is_some() => {
+                reason = reason.or(CloseReason::Lifetime);
+                break;
+            }
+        }
+    }
+
+    (reason, done1 && done2)
+}
+
+/// Copy until EOF at the rate allowed by `limiter`, then shut down the writer.
+///
+/// The transferred bytes are added to `bytes`, and recorded in `capture` when enabled.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: &Limiter,
+    bytes: &AtomicU64,
+    meter: &Meter,
+    capture: Option<(&TcpFlow, Direction)>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+
+    let result = async {
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                writer.shutdown().await?;
+
+                if let Some((flow, direction)) = capture {
+                    flow.fin(direction);
+                }
+                return Ok(());
+            }
+
+            writer.write_all(&buf[..n]).await?;
+
+            // TLS and WebSocket buffer what they write, the peer may be waiting for this chunk
+            writer.flush().await?;
+
+            if let Some((flow, direction)) = capture {
+                flow.data(direction, &buf[..n]);
+            }
+
+            bytes.fetch_add(n as u64, Ordering::Relaxed);
+            meter.touch();
+
+            limiter.wait(n).await;
+
+            // waiting for the limit does not make the pipe idle
+            meter.touch();
+        }
+    }
+    .await;
+
+    if let Err(e) = &result {
+        error!("Failed to copy: {}", e);
+
+        // still tell the peer, the other direction may be waiting for it
+        let _ = writer.shutdown().await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{self, DuplexStream};
+
+    /// Pipe a client to a target through `handle_forward_splitted`, returns both ends and the pipe.
+    fn pipe(
+        config: PipeConfig,
+    ) -> (
+        DuplexStream,
+        DuplexStream,
+        tokio::task::JoinHandle<PipeStats>,
+    ) {
+        let (client, client_side) = io::duplex(1024);
+        let (target_side, target) = io::duplex(1024);
+
+        let pipe = tokio::spawn(async move {
+            let (r1, w1) = io::split(client_side);
+            handle_forward_splitted(Box::new(r1), Box::new(w1), Box::new(target_side), &config)
+                .await
+        });
+
+        (client, target, pipe)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_closed_pipe_waits_for_late_response() {
+        let (mut client, mut target, pipe) = pipe(PipeConfig::default());
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // without an idle timeout, the response may come at any time
+        time::sleep(Duration::from_secs(600)).await;
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let stats = pipe.await.unwrap();
+        assert!(matches!(stats.reason, CloseReason::Eof));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_closed_pipe_idle_timeout() {
+        let config = PipeConfig {
+            idle_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let (mut client, mut target, pipe) = pipe(config);
+
+        client.shutdown().await.unwrap();
+        target.read_to_end(&mut Vec::new()).await.unwrap();
+
+        let stats = pipe.await.unwrap();
+        assert!(matches!(stats.reason, CloseReason::Idle));
+    }
 }
@@ -0,0 +1,521 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::{join, net, net::TcpStream, time};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use tracing::warn;
+
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+
+use crate::{
+    capture, crypto, dialer,
+    socks::TargetAddr,
+    tcp::NetStream,
+    ws::{self, WsDialer, WsListener},
+};
+
+/// How long a peer has to complete the TLS or WebSocket handshake.
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Handshake of a new connection, e.g. TLS.
+///
+/// It is awaited in the task of the connection, so that a slow peer does not hold the accept loop.
+/// Failures are logged by the handshake itself, the callers only drop the connection.
+pub type Connecting = BoxFuture<'static, Result<NetStream>>;
+
+/// Byte stream carried by TCP, a Unix domain socket, TLS or any other carrier.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    fn peer_addr(&self) -> Result<SocketAddr>;
+
+    /// Give up the plain socket under the stream for the splice fast path, carriers which
+    /// transform the bytes return themselves.
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream>;
+
+    fn split(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn AsyncRead + Unpin + Send>,
+        Box<dyn AsyncWrite + Unpin + Send>,
+    ) {
+        let (r, w) = io::split(self);
+        (Box::new(r), Box::new(w))
+    }
+}
+
+/// Source of incoming connections of a carrier.
+pub trait Listener: Send + Sync {
+    /// Wait for the next connection, returns its handshake and the peer address,
+    /// `capture::UNKNOWN_ADDR` for carriers without one.
+    fn accept(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>>;
+
+    fn local_addr(&self) -> Result<SocketAddr>;
+}
+
+/// Outgoing connections of a carrier to a fixed remote.
+pub trait Dialer: Send + Sync {
+    /// Connect to the remote, returns the handshake and the peer address,
+    /// `capture::UNKNOWN_ADDR` for carriers without one.
+    fn connect(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>>;
+}
+
+/// Plain socket under a transport, the bytes can be moved without leaving the kernel.
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(target_family = "unix")]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn into_stream(self) -> NetStream {
+        match self {
+            Socket::Tcp(stream) => Box::new(stream),
+            #[cfg(target_family = "unix")]
+            Socket::Unix(stream) => Box::new(stream),
+        }
+    }
+}
+
+impl From<TcpStream> for NetStream {
+    fn from(stream: TcpStream) -> Self {
+        Box::new(stream)
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl From<UnixStream> for NetStream {
+    fn from(stream: UnixStream) -> Self {
+        Box::new(stream)
+    }
+}
+
+impl Transport for TcpStream {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Ok(Socket::Tcp(*self))
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Transport for UnixStream {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix domain socket has no IP address",
+        ))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix domain socket has no IP address",
+        ))
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Ok(Socket::Unix(*self))
+    }
+}
+
+/// In-memory carrier, e.g. to run the protocol handlers in tests.
+impl Transport for DuplexStream {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "In-memory stream has no IP address",
+        ))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "In-memory stream has no IP address",
+        ))
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Err(self)
+    }
+}
+
+impl Transport for server::TlsStream<NetStream> {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Err(self)
+    }
+}
+
+impl Transport for client::TlsStream<NetStream> {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Err(self)
+    }
+}
+
+/// Listen on an address of the command line, the connections are TLS encrypted when `tls`
+/// is set, and carried by WebSocket for `ws://` and `wss://` addresses.
+pub async fn listen(addr: &str, tls: bool) -> Result<Box<dyn Listener>> {
+    let url = ws::Url::parse(addr);
+
+    let (addr, tls) = match &url {
+        Some(url) => (url.bind_addr(), tls || url.tls),
+        None => (addr.to_string(), tls),
+    };
+
+    let mut listener: Box<dyn Listener> = Box::new(TcpListener::bind(&addr).await?);
+
+    if tls {
+        listener = Box::new(TlsListener::new(listener, crypto::get_tls_acceptor(&addr)));
+    }
+
+    if let Some(url) = url {
+        listener = Box::new(WsListener::new(listener, &url.path));
+    }
+
+    Ok(listener)
+}
+
+/// Connect to an address of the command line through `dialer`, the connections are TLS
+/// encrypted when `tls` is set, and carried by WebSocket for `ws://` and `wss://` addresses.
+pub fn dial(
+    addr: &str,
+    tls: bool,
+    dialer: &dialer::Dialer,
+    ws: &ws::Config,
+) -> Result<Box<dyn Dialer>> {
+    let url = ws::Url::parse(addr);
+
+    let (target, tls) = match &url {
+        Some(url) => (url.connect_addr(), tls || url.tls),
+        None => (addr.to_string(), tls),
+    };
+
+    let mut remote: Box<dyn Dialer> = Box::new(TcpDialer::new(dialer.clone(), target.parse()?));
+
+    // the Host header of WebSocket is sent as SNI as well, for the reverse proxies in between
+    let host = match &url {
+        Some(url) => ws.host.clone().unwrap_or_else(|| url.authority.clone()),
+        None => "localhost".to_string(),
+    };
+
+    if tls {
+        let server_name = ServerName::try_from(ws::host_name(&host).to_string())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        remote = Box::new(TlsDialer::new(
+            remote,
+            crypto::get_tls_connector(),
+            server_name,
+        ));
+    }
+
+    if let Some(url) = url {
+        remote = Box::new(WsDialer::new(remote, &url.path, &host, &ws.headers));
+    }
+
+    Ok(remote)
+}
+
+pub struct TcpListener {
+    listener: net::TcpListener,
+}
+
+impl TcpListener {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        Ok(Self {
+            listener: net::TcpListener::bind(addr).await?,
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, addr) = self.listener.accept().await?;
+            Ok((ready(stream.into()), addr))
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// TCP connections to a target through the dialer, which may go through an upstream proxy.
+pub struct TcpDialer {
+    dialer: dialer::Dialer,
+    target: TargetAddr,
+}
+
+impl TcpDialer {
+    pub fn new(dialer: dialer::Dialer, target: TargetAddr) -> Self {
+        Self { dialer, target }
+    }
+}
+
+impl Dialer for TcpDialer {
+    fn connect(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let stream = self.dialer.connect(&self.target).await?;
+            let addr = stream.peer_addr()?;
+            Ok((ready(stream.into()), addr))
+        })
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub struct UnixDialer {
+    path: String,
+}
+
+#[cfg(target_family = "unix")]
+impl UnixDialer {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Dialer for UnixDialer {
+    fn connect(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let stream = UnixStream::connect(&self.path).await?;
+            Ok((ready(stream.into()), capture::UNKNOWN_ADDR))
+        })
+    }
+}
+
+/// Server side of TLS on top of the connections of another listener.
+pub struct TlsListener {
+    inner: Box<dyn Listener>,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    pub fn new(inner: Box<dyn Listener>, acceptor: TlsAcceptor) -> Self {
+        Self { inner, acceptor }
+    }
+}
+
+impl Listener for TlsListener {
+    fn accept(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let (connecting, addr) = self.inner.accept().await?;
+            let acceptor = self.acceptor.clone();
+
+            let connecting: Connecting = Box::pin(async move {
+                let stream = connecting.await?;
+                handshake("TLS", addr, acceptor.accept(stream))
+                    .await
+                    .map(|stream| Box::new(stream) as NetStream)
+            });
+
+            Ok((connecting, addr))
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Client side of TLS on top of the connections of another dialer, the certificate is not verified.
+pub struct TlsDialer {
+    inner: Box<dyn Dialer>,
+    connector: TlsConnector,
+    /// Sent as SNI, reverse proxies pick the virtual host with it.
+    server_name: ServerName<'static>,
+}
+
+impl TlsDialer {
+    pub fn new(
+        inner: Box<dyn Dialer>,
+        connector: TlsConnector,
+        server_name: ServerName<'static>,
+    ) -> Self {
+        Self {
+            inner,
+            connector,
+            server_name,
+        }
+    }
+}
+
+impl Dialer for TlsDialer {
+    fn connect(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let (connecting, addr) = self.inner.connect().await?;
+            let connector = self.connector.clone();
+            let server_name = self.server_name.clone();
+
+            let connecting: Connecting = Box::pin(async move {
+                let stream = connecting.await?;
+                handshake("TLS", addr, connector.connect(server_name, stream))
+                    .await
+                    .map(|stream| Box::new(stream) as NetStream)
+            });
+
+            Ok((connecting, addr))
+        })
+    }
+}
+
+/// Handshake of a connection which has none.
+fn ready(stream: NetStream) -> Connecting {
+    Box::pin(async move { Ok(stream) })
+}
+
+/// Wait for the handshake of one end of a pipe, the other end is shut down if it fails
+/// so that its peer does not wait for a pipe which will never open.
+pub async fn establish<S: AsyncWrite + Unpin>(
+    connecting: Connecting,
+    partner: &mut S,
+    partner_addr: impl Display,
+) -> Option<NetStream> {
+    match connecting.await {
+        Ok(stream) => Some(stream),
+        Err(_) => {
+            close_partner(partner, partner_addr).await;
+            None
+        }
+    }
+}
+
+/// Wait for the handshakes of both ends of a pipe, see `establish`.
+pub async fn establish_pair(
+    (first, first_addr): (Connecting, impl Display),
+    (second, second_addr): (Connecting, impl Display),
+) -> Option<(NetStream, NetStream)> {
+    match join!(first, second) {
+        (Ok(first), Ok(second)) => Some((first, second)),
+        (Ok(mut stream), Err(_)) => {
+            close_partner(&mut stream, first_addr).await;
+            None
+        }
+        (Err(_), Ok(mut stream)) => {
+            close_partner(&mut stream, second_addr).await;
+            None
+        }
+        (Err(_), Err(_)) => None,
+    }
+}
+
+async fn close_partner<S: AsyncWrite + Unpin>(stream: &mut S, addr: impl Display) {
+    warn!(
+        "Close connection with {}, the other end of the pipe failed",
+        addr
+    );
+    let _ = stream.shutdown().await;
+}
+
+/// Run a handshake with a deadline, so that idle or mismatched peers can not hold the task.
+pub(crate) async fn handshake<S>(
+    name: &str,
+    peer_addr: SocketAddr,
+    future: impl Future<Output = Result<S>>,
+) -> Result<S> {
+    let result = match time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), future).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "handshake timed out")),
+    };
+
+    result.inspect_err(|e| warn!("{} handshake with {} failed: {}", name, peer_addr, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::{self, CloseReason, PipeConfig};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn forward_duplex() {
+        let (client, mut client_peer) = io::duplex(64);
+        let (target, mut target_peer) = io::duplex(64);
+
+        let pipe = tokio::spawn(async move {
+            tcp::handle_forward(Box::new(client), Box::new(target), &PipeConfig::default()).await
+        });
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        target_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        target_peer.write_all(b"pong!").await.unwrap();
+        drop(target_peer);
+
+        let mut buf = Vec::new();
+        client_peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong!");
+        drop(client_peer);
+
+        let stats = pipe.await.unwrap();
+        assert_eq!((stats.sent, stats.received), (4, 5));
+        assert!(matches!(stats.reason, CloseReason::Eof));
+    }
+
+    #[tokio::test]
+    async fn establish_closes_partner() {
+        let (mut stream, mut peer) = io::duplex(64);
+
+        let failed: Connecting =
+            Box::pin(async { Err(Error::new(ErrorKind::ConnectionReset, "reset")) });
+        assert!(establish(failed, &mut stream, "peer").await.is_none());
+
+        let mut buf = Vec::new();
+        assert_eq!(peer.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn establish_pair_closes_partner() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let ok = ready(stream.into());
+        let failed: Connecting =
+            Box::pin(async { Err(Error::new(ErrorKind::ConnectionReset, "reset")) });
+        assert!(establish_pair((ok, "peer"), (failed, "other"))
+            .await
+            .is_none());
+
+        let mut buf = Vec::new();
+        assert_eq!(peer.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+}
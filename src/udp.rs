@@ -1,22 +1,28 @@
-use std::io::Result;
+use std::{io::Result, net::SocketAddr, sync::Mutex};
 
-use tokio::{net::UdpSocket, select};
+use tokio::{join, net::UdpSocket};
 use tracing::{error, info};
 
+use crate::tcp::PipeConfig;
+
 const BUFFER_SIZE: usize = 65535;
 
-pub async fn handle_local_forward(socket1: UdpSocket, socket2: UdpSocket) -> Result<()> {
-    let mut buf1 = vec![0u8; BUFFER_SIZE];
-    let mut buf2 = vec![0u8; BUFFER_SIZE];
+pub async fn handle_local_forward(
+    socket1: UdpSocket,
+    socket2: UdpSocket,
+    config: &PipeConfig,
+) -> Result<()> {
+    let throttle = config.limits.connection();
+    let capture = config.capture.as_ref();
 
-    #[allow(unused_assignments)]
-    let mut last_client_addr_1 = None;
-    let mut last_client_addr_2 = None;
+    // each direction runs on its own, so that a throttled one does not hold the other
+    let last_client_addr_1: Mutex<Option<SocketAddr>> = Mutex::new(None);
+    let last_client_addr_2: Mutex<Option<SocketAddr>> = Mutex::new(None);
 
     // handshake to keep the client address
     match socket1.recv_from(&mut [0u8; 4]).await {
         Ok((_, addr)) => {
-            last_client_addr_1 = Some(addr);
+            *last_client_addr_1.lock().unwrap() = Some(addr);
             info!("Handshake with client address {} success", addr)
         }
         Err(e) => {
@@ -25,44 +31,70 @@ pub async fn handle_local_forward(socket1: UdpSocket, socket2: UdpSocket) -> Res
         }
     }
 
-    loop {
-        select! {
-            Ok((len, addr)) = socket1.recv_from(&mut buf1) => {
-                last_client_addr_1 = Some(addr);
-                let data = &buf1[..len];
-
-                match last_client_addr_2 {
-                    Some(client_addr) => {
-                        if let Err(e) = socket2.send_to(data, client_addr).await {
-                            error!("Failed to forward to target: {}", e);
-                        }
+    let up = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let (len, addr) = match socket1.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            *last_client_addr_1.lock().unwrap() = Some(addr);
+            let data = &buf[..len];
+
+            let client_addr = *last_client_addr_2.lock().unwrap();
+            match client_addr {
+                Some(client_addr) => {
+                    if let Err(e) = socket2.send_to(data, client_addr).await {
+                        error!("Failed to forward to target: {}", e);
+                    } else if let Some(capture) = capture {
+                        capture.udp(addr, client_addr, data);
                     }
-                    None => error!("No client 2 address"),
+                    throttle.up.wait(len).await;
                 }
+                None => error!("No client 2 address"),
             }
-            Ok((len, addr)) = socket2.recv_from(&mut buf2) => {
-                last_client_addr_2 = Some(addr);
-                let data = &buf2[..len];
-
-                match last_client_addr_1 {
-                    Some(client_addr) => {
-                        if let Err(e) = socket1.send_to(data, client_addr).await {
-                            error!("Failed to forward to target: {}", e);
-                        }
+        }
+    };
+
+    let down = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let (len, addr) = match socket2.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            *last_client_addr_2.lock().unwrap() = Some(addr);
+            let data = &buf[..len];
+
+            let client_addr = *last_client_addr_1.lock().unwrap();
+            match client_addr {
+                Some(client_addr) => {
+                    if let Err(e) = socket1.send_to(data, client_addr).await {
+                        error!("Failed to forward to target: {}", e);
+                    } else if let Some(capture) = capture {
+                        capture.udp(addr, client_addr, data);
                     }
-                    None => error!("No client 1 address"),
+                    throttle.down.wait(len).await;
                 }
+                None => error!("No client 1 address"),
             }
         }
-    }
+    };
+
+    join!(up, down);
+
+    Ok(())
 }
 
 pub async fn handle_local_to_remote_forward(
     local_socket: UdpSocket,
     remote_socket: UdpSocket,
+    config: &PipeConfig,
 ) -> Result<()> {
-    let mut buf1 = vec![0u8; BUFFER_SIZE];
-    let mut buf2 = vec![0u8; BUFFER_SIZE];
+    let throttle = config.limits.connection();
+    let capture = config.capture.as_ref();
 
     // handshake to keep the client address
     // the unused packet may be sent to the real udp service (which will be forwarded)
@@ -76,35 +108,69 @@ pub async fn handle_local_to_remote_forward(
         );
     }
 
-    let mut last_client_addr = None;
-
-    loop {
-        select! {
-            Ok((len, addr)) = local_socket.recv_from(&mut buf1) => {
-                last_client_addr = Some(addr);
-                let data = &buf1[..len];
-
-                if let Err(e) = remote_socket.send(data).await {
-                    error!("Failed to forward: {}", e);
-                }
+    let remote_addr = remote_socket.peer_addr()?;
+    let last_client_addr: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+    // each direction runs on its own, so that a throttled one does not hold the other
+    let up = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let (len, addr) = match local_socket.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            *last_client_addr.lock().unwrap() = Some(addr);
+            let data = &buf[..len];
+
+            if let Err(e) = remote_socket.send(data).await {
+                error!("Failed to forward: {}", e);
+            } else if let Some(capture) = capture {
+                capture.udp(addr, remote_addr, data);
             }
-            Ok(len) = remote_socket.recv(&mut buf2) => {
-                match last_client_addr {
-                    Some(addr) => {
-                        let data = &buf2[..len];
-
-                        if let Err(e) = local_socket.send_to(data, addr).await {
-                            error!("Failed to forward: {}", e);
-                        }
-                    },
-                    None => error!("No client address"),
+            throttle.up.wait(len).await;
+        }
+    };
+
+    let down = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let len = match remote_socket.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+
+            let client_addr = *last_client_addr.lock().unwrap();
+            match client_addr {
+                Some(addr) => {
+                    let data = &buf[..len];
+
+                    if let Err(e) = local_socket.send_to(data, addr).await {
+                        error!("Failed to forward: {}", e);
+                    } else if let Some(capture) = capture {
+                        capture.udp(remote_addr, addr, data);
+                    }
+                    throttle.down.wait(len).await;
                 }
+                None => error!("No client address"),
             }
         }
-    }
+    };
+
+    join!(up, down);
+
+    Ok(())
 }
 
-pub async fn handle_remote_forward(socket1: UdpSocket, socket2: UdpSocket) -> Result<()> {
+pub async fn handle_remote_forward(
+    socket1: UdpSocket,
+    socket2: UdpSocket,
+    config: &PipeConfig,
+) -> Result<()> {
+    let throttle = config.limits.connection();
+    let capture = config.capture.as_ref();
+
     // handshake to keep the client address
     if let Err(e) = socket2.send(&[0u8; 4]).await {
         error!("Failed to handshake with remote address: {}", e);
@@ -116,23 +182,48 @@ pub async fn handle_remote_forward(socket1: UdpSocket, socket2: UdpSocket) -> Re
         );
     }
 
-    let mut buf1 = vec![0u8; BUFFER_SIZE];
-    let mut buf2 = vec![0u8; BUFFER_SIZE];
+    let (addr1, addr2) = (socket1.peer_addr()?, socket2.peer_addr()?);
 
-    loop {
-        select! {
-            Ok(len) = socket1.recv(&mut buf1) => {
-                let data = &buf1[..len];
-                if let Err(e) = socket2.send(data).await {
-                    error!("Failed to forward remote1 to remote2: {}", e);
-                }
+    // each direction runs on its own, so that a throttled one does not hold the other
+    let up = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let len = match socket1.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+            let data = &buf[..len];
+
+            if let Err(e) = socket2.send(data).await {
+                error!("Failed to forward remote1 to remote2: {}", e);
+            } else if let Some(capture) = capture {
+                capture.udp(addr1, addr2, data);
             }
-            Ok(len) = socket2.recv(&mut buf2) => {
-                let data = &buf2[..len];
-                if let Err(e) = socket1.send(data).await {
-                    error!("Failed to forward remote2 to remote1: {}", e);
-                }
+            throttle.up.wait(len).await;
+        }
+    };
+
+    let down = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let len = match socket2.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+            let data = &buf[..len];
+
+            if let Err(e) = socket1.send(data).await {
+                error!("Failed to forward remote2 to remote1: {}", e);
+            } else if let Some(capture) = capture {
+                capture.udp(addr2, addr1, data);
             }
+            throttle.down.wait(len).await;
         }
-    }
+    };
+
+    join!(up, down);
+
+    Ok(())
 }
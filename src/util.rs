@@ -1,4 +1,18 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tokio::{signal, time};
+
+/// Delay before dialing again after the first failure, doubled for each following one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay before dialing again after failures in a row.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 pub fn generate_random_string(length: usize) -> String {
     thread_rng()
@@ -7,3 +21,135 @@ pub fn generate_random_string(length: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, "Invalid port range");
+
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (start, end),
+        None => (s, s),
+    };
+
+    let start = start.trim().parse().map_err(|_| invalid())?;
+    let end = end.trim().parse().map_err(|_| invalid())?;
+
+    if start > end {
+        return Err(invalid());
+    }
+
+    Ok(start..=end)
+}
+
+/// Read the non-empty lines of a config file, lines starting with `#` are comments.
+pub fn read_config_lines(path: &str) -> Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Match `text` against a pattern where `*` matches any sequence of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack = None;
+
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if let Some((bp, bt)) = backtrack {
+            // let the last star consume one more character
+            pi = bp + 1;
+            ti = bt + 1;
+            backtrack = Some((bp, bt + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Parse a byte size with an optional binary suffix, e.g. `512`, `64K`, `10G` or `1.5MB`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid size: {}", s));
+
+    let upper = s.trim().to_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+
+    let (number, unit) = match number.char_indices().last() {
+        Some((i, 'K')) => (&number[..i], 1u64 << 10),
+        Some((i, 'M')) => (&number[..i], 1 << 20),
+        Some((i, 'G')) => (&number[..i], 1 << 30),
+        Some((i, 'T')) => (&number[..i], 1 << 40),
+        _ => (number, 1),
+    };
+
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(invalid());
+    }
+
+    Ok((number * unit as f64) as u64)
+}
+
+/// Parse credentials in the `user:pass` format.
+pub fn parse_credentials(s: &str) -> Result<(String, String)> {
+    s.split_once(':')
+        .map(|(user, pass)| (user.to_string(), pass.to_string()))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid authentication info, format: user:pass",
+            )
+        })
+}
+
+/// Wait for ctrl-c, or SIGTERM on unix (e.g. `kill` or a service manager stopping the process).
+pub async fn shutdown_signal() {
+    #[cfg(target_family = "unix")]
+    {
+        let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(_) => {
+                let _ = signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    let _ = signal::ctrl_c().await;
+}
+
+/// Delays of the loops dialing out on their own, so that an unreachable target is retried
+/// without spinning.
+#[derive(Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub async fn failed(&mut self) {
+        let delay = RETRY_DELAY.saturating_mul(2u32.saturating_pow(self.failures));
+        self.failures += 1;
+
+        time::sleep(delay.min(MAX_RETRY_DELAY)).await;
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
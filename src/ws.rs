@@ -0,0 +1,956 @@
+use std::cmp;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll, Waker};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    tcp::NetStream,
+    transport::{self, BoxFuture, Connecting, Dialer, Listener, Socket, Transport},
+};
+
+/// Appended to the key of the client to compute the accept key, see RFC 6455 section 1.3.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Largest payload sent in a single frame.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Headers of the upgrade requests sent by the WebSocket remotes.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Host header, the host of the address by default.
+    pub host: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+/// WebSocket address, format: ws[s]://[IP:]PORT[/PATH] to listen, ws[s]://HOST[:PORT][/PATH] to connect.
+pub struct Url {
+    pub tls: bool,
+    pub authority: String,
+    pub path: String,
+}
+
+/// Byte stream framed in binary WebSocket messages, see RFC 6455.
+///
+/// A close frame is sent when the stream is shut down and read as EOF, so that each
+/// direction can be closed on its own like a TCP half-close.
+pub struct WsStream {
+    inner: NetStream,
+    /// Clients mask the frames they send, servers must not.
+    client: bool,
+    /// Bytes read from the inner stream which have not been parsed yet.
+    rbuf: Vec<u8>,
+    /// Payload left in the current data frame.
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_offset: usize,
+    read_closed: bool,
+    /// Encoded frames which have not been written to the inner stream yet.
+    wbuf: Vec<u8>,
+    written: usize,
+    /// Length of the data frame in `wbuf` which is reported to the writer once it is written.
+    buffered: Option<usize>,
+    /// Writer waiting for `wbuf` to drain, the read side may drain it first when it answers a ping.
+    write_waker: Option<Waker>,
+    write_closed: bool,
+}
+
+impl Config {
+    /// Parse extra headers of the command line, format: NAME: VALUE.
+    pub fn new(host: Option<String>, headers: &[String]) -> Result<Self> {
+        let headers = headers
+            .iter()
+            .map(|header| {
+                let (name, value) = header.split_once(':').ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid WebSocket header: {}", header),
+                    )
+                })?;
+
+                Ok((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { host, headers })
+    }
+}
+
+impl Url {
+    /// Parse a `ws://` or `wss://` address, `None` for other addresses.
+    pub fn parse(addr: &str) -> Option<Self> {
+        let (tls, rest) = match addr.split_once("://") {
+            Some(("ws", rest)) => (false, rest),
+            Some(("wss", rest)) => (true, rest),
+            _ => return None,
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        Some(Self {
+            tls,
+            authority: authority.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Address to bind, a bare port listens on every interface.
+    pub fn bind_addr(&self) -> String {
+        match self.authority.parse::<u16>() {
+            Ok(port) => format!("0.0.0.0:{}", port),
+            Err(_) => self.authority.clone(),
+        }
+    }
+
+    /// Address to connect to, the port defaults to 80 for ws:// and 443 for wss://.
+    pub fn connect_addr(&self) -> String {
+        match self.port() {
+            Some(_) => self.authority.clone(),
+            None => format!("{}:{}", self.authority, if self.tls { 443 } else { 80 }),
+        }
+    }
+
+    fn port(&self) -> Option<u16> {
+        split_authority(&self.authority).1
+    }
+}
+
+/// Host of an authority without the port and the brackets of IPv6 literals.
+pub fn host_name(authority: &str) -> &str {
+    split_authority(authority)
+        .0
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+fn split_authority(authority: &str) -> (&str, Option<u16>) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if (!host.is_empty() && !host.contains(':')) || host.ends_with(']') => {
+            match port.parse() {
+                Ok(port) => (host, Some(port)),
+                Err(_) => (authority, None),
+            }
+        }
+        _ => (authority, None),
+    }
+}
+
+/// Server side of WebSocket on top of the connections of another listener.
+///
+/// Requests for other paths or without an upgrade are answered with 404, like a plain web server.
+pub struct WsListener {
+    inner: Box<dyn Listener>,
+    path: String,
+}
+
+impl WsListener {
+    pub fn new(inner: Box<dyn Listener>, path: &str) -> Self {
+        Self {
+            inner,
+            path: path.to_string(),
+        }
+    }
+}
+
+impl Listener for WsListener {
+    fn accept(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let (connecting, addr) = self.inner.accept().await?;
+            let path = self.path.clone();
+
+            let connecting: Connecting = Box::pin(async move {
+                let stream = connecting.await?;
+                transport::handshake("WebSocket", addr, accept(stream, &path))
+                    .await
+                    .map(|stream| Box::new(stream) as NetStream)
+            });
+
+            Ok((connecting, addr))
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Client side of WebSocket on top of the connections of another dialer.
+pub struct WsDialer {
+    inner: Box<dyn Dialer>,
+    /// Upgrade request without the key, which is generated for each connection.
+    request: String,
+}
+
+impl WsDialer {
+    pub fn new(
+        inner: Box<dyn Dialer>,
+        path: &str,
+        host: &str,
+        headers: &[(String, String)],
+    ) -> Self {
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n",
+            path, host
+        );
+
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        Self { inner, request }
+    }
+}
+
+impl Dialer for WsDialer {
+    fn connect(&self) -> BoxFuture<'_, Result<(Connecting, SocketAddr)>> {
+        Box::pin(async move {
+            let (connecting, addr) = self.inner.connect().await?;
+            let request = self.request.clone();
+
+            let connecting: Connecting = Box::pin(async move {
+                let stream = connecting.await?;
+                transport::handshake("WebSocket", addr, connect(stream, request))
+                    .await
+                    .map(|stream| Box::new(stream) as NetStream)
+            });
+
+            Ok((connecting, addr))
+        })
+    }
+}
+
+/// Answer the upgrade request of a client.
+async fn accept(mut stream: NetStream, path: &str) -> Result<WsStream> {
+    let (head, rest) = read_head(&mut stream).await?;
+    let (line, headers) = parse_head(&head)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next(), parts.next());
+
+    let key = header(&headers, "sec-websocket-key");
+    let upgrade = header(&headers, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+
+    let key = match (method, target.and_then(|t| t.split('?').next()), key) {
+        (Some("GET"), Some(target), Some(key)) if target == path && upgrade => key,
+        _ => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid upgrade request: {}", line),
+            ));
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(WsStream::new(stream, false, rest))
+}
+
+/// Send the upgrade request and check the answer of the server.
+async fn connect(mut stream: NetStream, mut request: String) -> Result<WsStream> {
+    let key = STANDARD.encode(rand::random::<[u8; 16]>());
+
+    request.push_str(&format!("Sec-WebSocket-Key: {}\r\n\r\n", key));
+    stream.write_all(request.as_bytes()).await?;
+
+    let (head, rest) = read_head(&mut stream).await?;
+    let (line, headers) = parse_head(&head)?;
+
+    let status = line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "101" {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Upgrade refused with status {}", status),
+        ));
+    }
+
+    if header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid Sec-WebSocket-Accept",
+        ));
+    }
+
+    Ok(WsStream::new(stream, true, rest))
+}
+
+/// Read an HTTP head, the bytes after it already belong to the WebSocket stream.
+async fn read_head(stream: &mut NetStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUFFER_SIZE];
+
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), rest));
+        }
+
+        if buf.len() >= MAX_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "Head too large"));
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Head truncated"));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn parse_head(head: &str) -> Result<(&str, Vec<(&str, &str)>)> {
+    let mut lines = head.trim_end().split("\r\n");
+    let line = lines.next().unwrap_or_default();
+
+    let headers = lines
+        .map(|line| {
+            line.split_once(':')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid header"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((line, headers))
+}
+
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| *v)
+}
+
+/// Unmask the payload of a frame, `offset` is the position in the payload modulo 4.
+fn unmask(mask: Option<[u8; 4]>, offset: &mut usize, data: &mut [u8]) {
+    if let Some(key) = mask {
+        for b in data {
+            *b ^= key[*offset];
+            *offset = (*offset + 1) % 4;
+        }
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, GUID).as_bytes(),
+    );
+    STANDARD.encode(hash.as_ref())
+}
+
+impl WsStream {
+    fn new(inner: NetStream, client: bool, rbuf: Vec<u8>) -> Self {
+        Self {
+            inner,
+            client,
+            rbuf,
+            remaining: 0,
+            mask: None,
+            mask_offset: 0,
+            read_closed: false,
+            wbuf: Vec::new(),
+            written: 0,
+            buffered: None,
+            write_waker: None,
+            write_closed: false,
+        }
+    }
+
+    /// Parse the next frame header in the read buffer, returns false when more bytes are needed.
+    ///
+    /// Control frames are handled here: close ends the read side and ping queues a pong.
+    fn parse_frame(&mut self) -> Result<bool> {
+        let buf = &self.rbuf;
+        if buf.len() < 2 {
+            return Ok(false);
+        }
+
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+        if buf[0] & 0x70 != 0 {
+            return Err(invalid("Reserved bits set in WebSocket frame"));
+        }
+
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+
+        if masked == self.client {
+            return Err(invalid("Unexpected masking of WebSocket frame"));
+        }
+
+        let (len, mut pos) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(false),
+            len => (len as u64, 2),
+        };
+
+        let mask = match masked {
+            true if buf.len() < pos + 4 => return Ok(false),
+            true => {
+                pos += 4;
+                Some(buf[pos - 4..pos].try_into().unwrap())
+            }
+            false => None,
+        };
+
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                self.rbuf.drain(..pos);
+                self.remaining = len;
+                self.mask = mask;
+                self.mask_offset = 0;
+            }
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if len > 125 {
+                    return Err(invalid("WebSocket control frame too large"));
+                }
+
+                let end = pos + len as usize;
+                if buf.len() < end {
+                    return Ok(false);
+                }
+
+                let mut payload: Vec<u8> = self.rbuf.drain(..end).skip(pos).collect();
+                unmask(mask, &mut 0, &mut payload);
+
+                match opcode {
+                    OP_CLOSE => self.read_closed = true,
+                    OP_PING if !self.write_closed => self.encode(OP_PONG, &payload),
+                    _ => {}
+                }
+            }
+            _ => return Err(invalid("Unknown WebSocket opcode")),
+        }
+
+        Ok(true)
+    }
+
+    fn encode(&mut self, opcode: u8, payload: &[u8]) {
+        let mask_bit = if self.client { 0x80 } else { 0 };
+
+        self.wbuf.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => self.wbuf.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                self.wbuf.push(mask_bit | 126);
+                self.wbuf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.wbuf.push(mask_bit | 127);
+                self.wbuf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        match self.client {
+            true => {
+                let key = rand::random::<[u8; 4]>();
+                self.wbuf.extend_from_slice(&key);
+                self.wbuf
+                    .extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            false => self.wbuf.extend_from_slice(payload),
+        }
+    }
+
+    /// Write the encoded frames to the inner stream.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.written < self.wbuf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+
+        self.wbuf.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Drain the encoded frames for the write side, which is woken up if the read side drains them.
+    fn poll_drain_writer(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let poll = self.poll_drain(cx);
+        if poll.is_pending() {
+            self.write_waker = Some(cx.waker().clone());
+        }
+        poll
+    }
+
+    /// Push the queued frames from the read side, so that a pong is sent while nothing is written.
+    fn drain_from_reader(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        if self.wbuf.is_empty() {
+            return Ok(());
+        }
+
+        if let Poll::Ready(Err(e)) = self.poll_drain(cx) {
+            return Err(e);
+        }
+
+        // the inner stream keeps one waker per direction, the one of the writer may be replaced
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        this.drain_from_reader(cx)?;
+
+        loop {
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.remaining > 0 {
+                let limit = cmp::min(this.remaining, buf.remaining() as u64) as usize;
+
+                let n = match this.rbuf.is_empty() {
+                    false => {
+                        let n = cmp::min(limit, this.rbuf.len());
+                        unmask(this.mask, &mut this.mask_offset, &mut this.rbuf[..n]);
+                        buf.put_slice(&this.rbuf[..n]);
+                        this.rbuf.drain(..n);
+                        n
+                    }
+                    // read the payload straight into the buffer of the caller
+                    true => {
+                        let mut payload = ReadBuf::new(buf.initialize_unfilled_to(limit));
+                        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut payload))?;
+
+                        let n = payload.filled().len();
+                        if n == 0 {
+                            return Poll::Ready(Err(Error::new(
+                                ErrorKind::UnexpectedEof,
+                                "WebSocket frame truncated",
+                            )));
+                        }
+
+                        unmask(this.mask, &mut this.mask_offset, payload.filled_mut());
+                        buf.advance(n);
+                        n
+                    }
+                };
+
+                this.remaining -= n as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.parse_frame()? {
+                // answer a ping right away
+                this.drain_from_reader(cx)?;
+                continue;
+            }
+
+            let mut chunk = [0; READ_BUFFER_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+            if chunk.filled().is_empty() {
+                // a peer going away without a close frame still ends the stream
+                return match this.rbuf.is_empty() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Ready(Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "WebSocket frame truncated",
+                    ))),
+                };
+            }
+
+            this.rbuf.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+
+        if this.write_closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+
+        // a frame encoded by a previous call, the caller retries with the same buffer
+        if let Some(n) = this.buffered {
+            ready!(this.poll_drain_writer(cx))?;
+            this.buffered = None;
+            return Poll::Ready(Ok(n));
+        }
+
+        ready!(this.poll_drain_writer(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = cmp::min(buf.len(), MAX_FRAME_SIZE);
+        this.encode(OP_BINARY, &buf[..n]);
+
+        // the bytes are only reported written once their frame has reached the inner stream
+        this.buffered = Some(n);
+        ready!(this.poll_drain_writer(cx))?;
+        this.buffered = None;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain_writer(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if !this.write_closed {
+            // normal closure
+            this.encode(OP_CLOSE, &1000u16.to_be_bytes());
+            this.write_closed = true;
+        }
+
+        ready!(this.poll_drain_writer(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl Transport for WsStream {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn into_socket(self: Box<Self>) -> std::result::Result<Socket, NetStream> {
+        Err(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{self, DuplexStream};
+    use tokio::time;
+
+    /// WebSocket end `client` or server, talking to a raw peer which checks the frames.
+    fn pair(client: bool, capacity: usize) -> (WsStream, DuplexStream) {
+        let (stream, peer) = io::duplex(capacity);
+        (WsStream::new(Box::new(stream), client, Vec::new()), peer)
+    }
+
+    fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let mut frame = vec![0x80 | opcode];
+
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        match mask {
+            Some(key) => {
+                frame.extend_from_slice(&key);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+
+        frame
+    }
+
+    /// Read a frame of the peer, returns its opcode, mask and unmasked payload.
+    async fn read_frame(peer: &mut DuplexStream) -> (u8, Option<[u8; 4]>, Vec<u8>) {
+        let mut head = [0u8; 2];
+        peer.read_exact(&mut head).await.unwrap();
+
+        let len = match head[1] & 0x7f {
+            126 => peer.read_u16().await.unwrap() as usize,
+            127 => peer.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+
+        let mask = match head[1] & 0x80 {
+            0 => None,
+            _ => {
+                let mut key = [0u8; 4];
+                peer.read_exact(&mut key).await.unwrap();
+                Some(key)
+            }
+        };
+
+        let mut payload = vec![0u8; len];
+        peer.read_exact(&mut payload).await.unwrap();
+        unmask(mask, &mut 0, &mut payload);
+
+        (head[0] & 0x0f, mask, payload)
+    }
+
+    #[tokio::test]
+    async fn masking() {
+        let (mut client, mut peer) = pair(true, 1024);
+        client.write_all(b"hello").await.unwrap();
+
+        let (opcode, mask, payload) = read_frame(&mut peer).await;
+        assert_eq!(opcode, OP_BINARY);
+        assert!(mask.is_some());
+        assert_eq!(payload, b"hello");
+
+        let (mut server, mut peer) = pair(false, 1024);
+        server.write_all(b"hello").await.unwrap();
+
+        let (_, mask, payload) = read_frame(&mut peer).await;
+        assert!(mask.is_none());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn reject_wrong_masking() {
+        // servers only accept masked frames, clients only unmasked ones
+        let (mut server, mut peer) = pair(false, 1024);
+        peer.write_all(&frame(OP_BINARY, b"hello", None))
+            .await
+            .unwrap();
+
+        let e = server.read(&mut [0u8; 16]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let (mut client, mut peer) = pair(true, 1024);
+        peer.write_all(&frame(OP_BINARY, b"hello", Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+
+        let e = client.read(&mut [0u8; 16]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn extended_lengths() {
+        for len in [125, 126, 65535, 65536] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+            let (mut client, mut peer) = pair(true, 1 << 20);
+            client.write_all(&data).await.unwrap();
+
+            let (opcode, _, payload) = read_frame(&mut peer).await;
+            assert_eq!(opcode, OP_BINARY);
+            assert_eq!(payload, data, "{}", len);
+
+            let (mut server, mut peer) = pair(false, 1 << 20);
+            peer.write_all(&frame(OP_BINARY, &data, Some([9, 8, 7, 6])))
+                .await
+                .unwrap();
+            drop(peer);
+
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, data, "{}", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn large_write_is_split() {
+        let (mut server, mut peer) = pair(false, 1 << 20);
+        server
+            .write_all(&vec![7u8; MAX_FRAME_SIZE + 1])
+            .await
+            .unwrap();
+
+        assert_eq!(read_frame(&mut peer).await.2.len(), MAX_FRAME_SIZE);
+        assert_eq!(read_frame(&mut peer).await.2, [7]);
+    }
+
+    #[tokio::test]
+    async fn ping_pong() {
+        let (mut server, mut peer) = pair(false, 1024);
+        peer.write_all(&frame(OP_PING, b"ping", Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+
+        // the pong is sent by the read side, while nothing is written
+        let read = time::timeout(Duration::from_millis(100), server.read(&mut [0u8; 16])).await;
+        assert!(read.is_err());
+
+        let (opcode, _, payload) = read_frame(&mut peer).await;
+        assert_eq!(opcode, OP_PONG);
+        assert_eq!(payload, b"ping");
+
+        // control frames may come between the frames of a message
+        peer.write_all(&frame(OP_PONG, b"", Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+        peer.write_all(&frame(OP_BINARY, b"data", Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+    }
+
+    #[tokio::test]
+    async fn write_waits_for_the_frame() {
+        let (mut server, mut peer) = pair(false, 16);
+        let data = [5u8; 100];
+
+        // the frame does not fit in the inner stream, so the write is not done
+        let write = time::timeout(Duration::from_millis(100), server.write(&data)).await;
+        assert!(write.is_err());
+
+        let reader = tokio::spawn(async move { read_frame(&mut peer).await });
+
+        // the retried write reports the frame encoded by the first one
+        assert_eq!(server.write(&data).await.unwrap(), 100);
+        assert_eq!(reader.await.unwrap().2, data);
+    }
+
+    #[tokio::test]
+    async fn pong_drained_by_reader_while_writing() {
+        let (stream, mut peer) = io::duplex(16);
+        let server = WsStream::new(Box::new(stream), false, Vec::new());
+        let (mut reader, mut writer) = io::split(server);
+
+        // the writer waits on a full inner stream
+        let write = tokio::spawn(async move {
+            writer.write_all(&[5u8; 100]).await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        let read = tokio::spawn(async move { reader.read(&mut [0u8; 16]).await });
+
+        time::sleep(Duration::from_millis(50)).await;
+        peer.write_all(&frame(OP_PING, b"ping", Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+
+        // both the data frame and the pong are written in order, and the writer is woken up
+        // although the read side drained its frame
+        let frames = async {
+            let (opcode, _, payload) = read_frame(&mut peer).await;
+            assert_eq!((opcode, payload.len()), (OP_BINARY, 100));
+
+            let (opcode, _, payload) = read_frame(&mut peer).await;
+            assert_eq!((opcode, payload), (OP_PONG, b"ping".to_vec()));
+
+            write.await.unwrap();
+        };
+        time::timeout(Duration::from_secs(5), frames)
+            .await
+            .expect("writer not woken up");
+
+        read.abort();
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let (mut client, mut peer) = pair(true, 1024);
+        client.shutdown().await.unwrap();
+
+        let (opcode, _, payload) = read_frame(&mut peer).await;
+        assert_eq!(opcode, OP_CLOSE);
+        assert_eq!(payload, 1000u16.to_be_bytes());
+
+        // the other direction stays open until the peer closes it as well
+        peer.write_all(&frame(OP_BINARY, b"last", None))
+            .await
+            .unwrap();
+        peer.write_all(&frame(OP_CLOSE, &1000u16.to_be_bytes(), None))
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"last");
+
+        assert_eq!(
+            client.write(b"late").await.unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_frames() {
+        let full = frame(OP_BINARY, b"hello", Some([1, 2, 3, 4]));
+
+        // cut in the header, in the mask and in the payload
+        for len in [1, 4, 8] {
+            let (mut server, mut peer) = pair(false, 1024);
+            peer.write_all(&full[..len]).await.unwrap();
+            drop(peer);
+
+            let mut received = Vec::new();
+            let e = server.read_to_end(&mut received).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{}", len);
+        }
+
+        // a peer going away between frames is a plain EOF
+        let (mut server, mut peer) = pair(false, 1024);
+        peer.write_all(&full).await.unwrap();
+        drop(peer);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn oversized_control_frame() {
+        let (mut server, mut peer) = pair(false, 1024);
+        peer.write_all(&frame(OP_PING, &[0; 126], Some([1, 2, 3, 4])))
+            .await
+            .unwrap();
+
+        let e = server.read(&mut [0u8; 16]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}